@@ -1,2 +1,4 @@
 pub mod completion;
+pub mod rotate_password;
+pub mod schema;
 pub mod sync_db;
@@ -1,20 +1,43 @@
 use std::io;
+use std::path::PathBuf;
 
+use anyhow::Result;
 use clap::Args;
 use clap::CommandFactory;
+use clap::builder::PossibleValuesParser;
 use clap_complete::generate;
 use clap_complete::Shell;
 
 use crate::Cli;
+use crate::config;
+use crate::error::Error;
 
 const CARGO_PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
 #[derive(Args)]
-pub struct Completion;
+pub struct Completion {
+    #[arg(long, help = "env path, db config names of env are completed for --only and --config")]
+    env: Option<PathBuf>,
+}
 
 impl Completion {
-    pub fn execute(&self) {
-        let shell = Shell::from_env().expect("unknown shell");
-        generate(shell, &mut Cli::command(), CARGO_PKG_NAME, &mut io::stdout());
+    pub fn execute(&self) -> Result<()> {
+        let shell = Shell::from_env().ok_or_else(|| Error::Config("unknown shell, SHELL env is not supported".to_owned()))?;
+        let mut command = Cli::command();
+        if let Some(env_dir) = &self.env {
+            // possible values only affect generated script, patterns not in list are still accepted by gm
+            let names: Vec<String> = config::db_config_paths(env_dir)?
+                .iter()
+                .map(|path| config::db_config_name(env_dir, path))
+                .collect();
+            command = command.mut_subcommand("db", |db| {
+                db.mut_arg("only", |arg| arg.value_parser(PossibleValuesParser::new(names.clone())))
+                    .mut_subcommand("rotate", |rotate| {
+                        rotate.mut_arg("config", |arg| arg.value_parser(PossibleValuesParser::new(names.clone())))
+                    })
+            });
+        }
+        generate(shell, &mut command, CARGO_PKG_NAME, &mut io::stdout());
+        Ok(())
     }
 }
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::TimeDelta;
+use chrono::Utc;
+use clap::Args;
+use tracing::info;
+
+use crate::config;
+use crate::config::db_config::Auth;
+use crate::config::db_config::DBConfig;
+use crate::config::db_config::User;
+use crate::config::defaults::Defaults;
+use crate::db::Database;
+use crate::error::Error;
+use crate::gcloud::secret_manager;
+use crate::gcloud::sql_admin;
+
+#[derive(Args)]
+pub struct RotatePassword {
+    #[arg(long, help = "env path")]
+    env: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "db config name, e.g. orders for db/orders.json or payments/orders for db/payments/orders.yaml"
+    )]
+    config: String,
+    #[arg(long, help = "user to rotate, default to all PASSWORD users")]
+    user: Option<String>,
+    #[arg(long, help = "disable secret versions which were replaced more than given days ago")]
+    grace_days: Option<i64>,
+}
+
+impl RotatePassword {
+    pub async fn execute(&self) -> Result<()> {
+        rustls::crypto::aws_lc_rs::default_provider().install_default().unwrap();
+
+        let env_dir = self.env.as_deref().unwrap_or(Path::new("."));
+        let path = config::db_config_path(env_dir, &self.config)?;
+        info!(
+            "rotate db password, config={}",
+            fs::canonicalize(&path).map_err(Error::io(&path))?.to_string_lossy()
+        );
+        let config = DBConfig::load(&path, &Defaults::load(env_dir)?)?;
+
+        let users: Vec<&User> = config
+            .users
+            .iter()
+            .filter(|user| matches!(user.auth, Auth::Password))
+            .filter(|user| self.user.as_ref().is_none_or(|name| &user.name == name))
+            .collect();
+        if let (Some(name), true) = (&self.user, users.is_empty()) {
+            return Err(Error::Config(format!("password user not found in config, user={name}")).into());
+        }
+
+        let instance = sql_admin::get_sql_instance(&config.project, &config.instance).await?;
+        let public_ip = instance.public_address()?;
+        let root_password = secret_manager::get(&config.project, &config.root_secret)
+            .await?
+            .ok_or_else(|| Error::Config(format!("root secret not found, run sync first, secret={}", config.root_secret)))?;
+        let mut database = Database::create_database(&config.db_type, public_ip, &root_password).await?;
+
+        for user in users {
+            let secret = user.secret.as_ref().unwrap();
+            info!(user = user.name, secret, "rotate password");
+
+            // secret is updated first, so new password is never lost, rerun rotate if altering user failed
+            let password = secret_manager::generate_password();
+            secret_manager::add_secret_version(&config.project, secret, &password).await?;
+            database
+                .change_password(&user.name, &password)
+                .await
+                .with_context(|| format!("failed to change password, rerun rotate to fix, user={}", user.name))?;
+
+            let db = user.db.as_ref().or(config.dbs.first()).map(String::as_str).unwrap_or("postgres");
+            Database::verify_login(&config.db_type, public_ip, &user.name, &password, db)
+                .await
+                .with_context(|| format!("failed to login with new password, user={}", user.name))?;
+            info!(user = user.name, "verified login with new password");
+
+            if let Some(grace_days) = self.grace_days {
+                disable_replaced_versions(&config.project, secret, grace_days).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// a version is replaced when next version is created, and kept enabled within grace period for clients still using it
+async fn disable_replaced_versions(project: &str, secret: &str, grace_days: i64) -> Result<()> {
+    let cutoff = Utc::now() - TimeDelta::days(grace_days);
+    let mut versions = secret_manager::enabled_versions(project, secret).await?;
+    versions.sort_by_key(|version| version.create_time);
+    for pair in versions.windows(2) {
+        let (version, next) = (&pair[0], &pair[1]);
+        if next.create_time < cutoff {
+            secret_manager::disable_version(&version.name).await?;
+        }
+    }
+    Ok(())
+}
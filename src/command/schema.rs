@@ -0,0 +1,87 @@
+use anyhow::Result;
+use clap::Args;
+use clap::Subcommand;
+use schemars::schema_for;
+use serde_json::Value;
+
+use crate::config::db_config::DBConfig;
+
+#[derive(Args)]
+pub struct Schema {
+    #[command(subcommand)]
+    command: SchemaCommands,
+}
+
+#[derive(Subcommand)]
+pub enum SchemaCommands {
+    #[command(about = "print json schema of db config")]
+    DB {
+        #[arg(
+            long,
+            help = "make fields optional which may be inherited from env.json or db/_defaults.json, for db config files"
+        )]
+        partial: bool,
+    },
+}
+
+impl Schema {
+    pub fn execute(&self) -> Result<()> {
+        let schema = match self.command {
+            SchemaCommands::DB { partial } => db_config_schema(partial),
+        };
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        Ok(())
+    }
+}
+
+// full schema is of merged config, defaults are merged into objects field by field, so nested object fields may be inherited too,
+// arrays are replaced as a whole, e.g. users, so fields of array items are still required
+fn db_config_schema(partial: bool) -> Value {
+    let mut schema = schema_for!(DBConfig).to_value();
+    if partial {
+        let refs: Vec<String> = schema["properties"]
+            .as_object()
+            .into_iter()
+            .flat_map(|properties| properties.values())
+            .filter_map(|property| property["$ref"].as_str())
+            .filter_map(|reference| reference.strip_prefix("#/$defs/"))
+            .map(str::to_owned)
+            .collect();
+        for reference in refs {
+            if let Some(definition) = schema["$defs"][&reference].as_object_mut() {
+                definition.remove("required");
+            }
+        }
+        if let Some(schema) = schema.as_object_mut() {
+            schema.remove("required");
+        }
+    }
+    schema
+}
+
+#[cfg(test)]
+mod test {
+    use crate::command::schema::db_config_schema;
+
+    #[test]
+    fn db_config_schema_of_merged_config() {
+        let schema = db_config_schema(false);
+        assert!(schema.pointer("/properties/rootSecret").is_some());
+        assert!(schema.pointer("/properties/type").is_some());
+        assert!(schema.pointer("/$defs/User/properties/iamType").is_some());
+        assert!(schema["required"].as_array().unwrap().contains(&serde_json::json!("project")));
+        assert_eq!(
+            schema.pointer("/$defs/Role/enum").unwrap(),
+            &serde_json::json!(["APP", "MIGRATION", "VIEWER", "REPLICATION"])
+        );
+    }
+
+    #[test]
+    fn partial_db_config_schema() {
+        let schema = db_config_schema(true);
+        assert!(schema.pointer("/properties/rootSecret").is_some());
+        assert!(schema.get("required").is_none());
+        assert!(schema.pointer("/$defs/Endpoint/required").is_none());
+        assert!(schema.pointer("/$defs/User/required").is_some());
+    }
+}
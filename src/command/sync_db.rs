@@ -1,25 +1,110 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Result;
+use anyhow::anyhow;
 use clap::Args;
+use glob::MatchOptions;
+use glob::Pattern;
 
+use plan::Action;
+use plan::Plan;
+use summary::SyncResult;
+use summary::print_summary;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use tracing::error;
 use tracing::info;
+use tracing::info_span;
+use tracing::warn;
 
+use crate::config;
 use crate::config::db_config::Auth;
 use crate::config::db_config::DBConfig;
 use crate::config::db_config::DBType;
+use crate::config::db_config::User;
+use crate::config::defaults::Defaults;
+use crate::db;
 use crate::db::Database;
+use crate::db::Password;
+use crate::db::Prune;
+use crate::db::PruneUser;
+use crate::db::Selection;
+use crate::db::State;
+use crate::error::Error;
 use crate::gcloud::secret_manager;
 use crate::gcloud::sql_admin;
+use crate::gcloud::sql_admin::DatabaseFlag;
+use crate::gcloud::sql_admin::GetSQLInstanceResponse;
 use crate::kube;
-use crate::util::json;
+
+mod plan;
+mod summary;
 
 #[derive(Args)]
 pub struct SyncDB {
     #[arg(long, help = "env path")]
     env: Option<PathBuf>,
+    #[arg(long, help = "print plan without applying changes")]
+    dry_run: bool,
+    #[arg(
+        long,
+        help = "check drift between config and instance, exit with code 3 if any",
+        conflicts_with = "dry_run"
+    )]
+    check: bool,
+    #[arg(long, help = "lock or drop users not in config, and warn dbs not in config")]
+    prune: Option<PruneUser>,
+    #[arg(long, help = "drop dbs not in config", requires = "prune")]
+    prune_dbs: bool,
+    #[arg(long, help = "continue with other configs on error, and print summary at end")]
+    keep_going: bool,
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "number of configs to sync concurrently, configs of same instance are synced one by one"
+    )]
+    parallel: u16,
+    #[arg(
+        long,
+        visible_aliases = ["config", "filter"],
+        value_name = "PATTERN",
+        help = "only sync configs matching config name, file name glob, instance name or db type, e.g. orders-db, orders-*, MySQL"
+    )]
+    only: Vec<String>,
+    #[arg(
+        long = "user",
+        value_name = "USER",
+        conflicts_with = "prune",
+        help = "only sync given users of config, email for IAM users"
+    )]
+    users: Vec<String>,
+    #[arg(
+        long = "db",
+        value_name = "DB",
+        conflicts_with = "prune",
+        help = "only sync given dbs of config, and grants on them"
+    )]
+    dbs: Vec<String>,
+}
+
+// configs not selected by --only are only loaded for prune
+struct LoadedConfig {
+    path: PathBuf,
+    config: DBConfig,
+    selected: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Apply,
+    DryRun,
+    Check,
 }
 
 impl SyncDB {
@@ -28,87 +113,436 @@ impl SyncDB {
 
         let env_dir = self.env.as_deref().unwrap_or(Path::new("."));
         if !env_dir.exists() {
-            panic!("env dir doesn't exist, dir={}", env_dir.to_string_lossy());
+            return Err(Error::Config(format!("env dir doesn't exist, dir={}", env_dir.to_string_lossy())).into());
         }
-        let absolute_env_dir = fs::canonicalize(env_dir).unwrap_or_else(|err| panic!("{err}"));
+        let absolute_env_dir = fs::canonicalize(env_dir).map_err(Error::io(env_dir))?;
         info!("env: {}", absolute_env_dir.to_string_lossy());
 
-        let paths = db_config_paths(env_dir);
+        let paths = config::db_config_paths(env_dir)?;
+        let mode = self.mode();
+        // configs are set per instance, as prune keeps users and dbs of all configs of instance
+        let prune = self.prune.map(|users| Prune {
+            users,
+            dbs: self.prune_dbs,
+            configs: &[],
+            group_members: &[],
+        });
+        let patterns = self.patterns()?;
+        let defaults = Defaults::load(env_dir)?;
 
+        let mut results = vec![];
+        // configs of same instance are synced one by one, as they share root user and may touch same roles,
+        // with prune, configs not selected by --only are kept in group to compute users and dbs to keep, but not synced
+        let mut groups: Vec<(String, Vec<LoadedConfig>)> = vec![];
         for path in paths {
-            info!("sync db config, config={}", path.to_string_lossy());
-            let content = fs::read_to_string(path).unwrap_or_else(|err| panic!("{err}"));
-            let config: DBConfig = json::from_json(&content);
-            config.validate();
+            let name_matched = patterns.is_empty() || patterns.iter().any(|pattern| name_matches(pattern, env_dir, &path));
+            let config = match DBConfig::load(&path, &defaults) {
+                Ok(config) => config,
+                // instance of invalid config is unknown, so it may share instance with any config
+                Err(err) if prune.is_some() => {
+                    return Err(anyhow::Error::from(err).context(format!(
+                        "failed to load db config, all configs must be valid to prune, config={}",
+                        path.to_string_lossy()
+                    )));
+                }
+                // instance and db type are unknown, so invalid config is only reported if selected by name
+                Err(err) if !name_matched => {
+                    warn!(
+                        "failed to load db config, skip as name doesn't match, config={}, error={err}",
+                        path.to_string_lossy()
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    let err = anyhow::Error::from(err);
+                    if !self.keep_going {
+                        return Err(err.context(format!("failed to sync db, config={}", path.to_string_lossy())));
+                    }
+                    error!("failed to load db config, config={}, error={err:#}", path.to_string_lossy());
+                    let mut result = SyncResult::new(&path);
+                    result.error = Some(format!("{err:#}"));
+                    results.push(result);
+                    continue;
+                }
+            };
+            let selected = name_matched || patterns.iter().any(|pattern| config_matches(pattern, &config));
+            if !selected && prune.is_none() {
+                continue;
+            }
+            let instance = format!("{}/{}", config.project, config.instance);
+            match groups.iter_mut().find(|(key, _)| *key == instance) {
+                Some((_, configs)) => configs.push(LoadedConfig { path, config, selected }),
+                None => groups.push((instance, vec![LoadedConfig { path, config, selected }])),
+            }
+        }
+        groups.retain(|(_, configs)| configs.iter().any(|loaded| loaded.selected));
+
+        if !self.only.is_empty() && results.is_empty() && groups.is_empty() {
+            return Err(Error::Config(format!("no db config matches, only={}", self.only.join(","))).into());
+        }
+
+        let selection = Arc::new(Selection {
+            users: self.users.clone(),
+            dbs: self.dbs.clone(),
+        });
+        let semaphore = Arc::new(Semaphore::new(self.parallel as usize));
+        let mut tasks = JoinSet::new();
+        for (_, configs) in groups {
+            let semaphore = semaphore.clone();
+            let env_dir = env_dir.to_path_buf();
+            let selection = selection.clone();
+            let keep_going = self.keep_going;
+            tasks.spawn(async move {
+                // semaphore is closed on error, groups not started yet are skipped
+                let Ok(_permit) = semaphore.acquire_owned().await else {
+                    return (vec![], None);
+                };
+                sync_configs(configs, &env_dir, mode, prune, &selection, keep_going).await
+            });
+        }
+        // running groups are not aborted on error, as they may be applying, e.g. between creating secret and setting password
+        let mut first_err = None;
+        while let Some(outcome) = tasks.join_next().await {
+            let (group_results, err) = outcome.unwrap_or_else(|err| (vec![], Some(err.into())));
+            results.extend(group_results);
+            if let Some(err) = err {
+                semaphore.close();
+                first_err.get_or_insert(err);
+            }
+        }
+        if let Some(err) = first_err {
+            return Err(err);
+        }
+        results.sort_by(|a, b| a.config.cmp(&b.config));
 
-            let instance = sql_admin::get_sql_instance(&config.project, &config.instance).await;
-            let public_ip = instance.public_address();
-            let private_ip = instance.private_address();
-            sync_db(&config, public_ip).await?;
-            sync_kube_endpoints(&config, env_dir, private_ip);
+        if self.keep_going {
+            print_summary(&results);
+            let failed = results.iter().filter(|result| result.error.is_some()).count();
+            if failed > 0 {
+                return Err(anyhow!("failed to sync {failed} of {} db configs", results.len()));
+            }
+        }
+
+        let drifted = results.iter().filter(|result| result.drifted).count();
+        if mode == Mode::Check && drifted > 0 {
+            return Err(Error::Drift(drifted).into());
         }
 
         Ok(())
     }
+
+    fn patterns(&self) -> Result<Vec<Pattern>> {
+        self.only
+            .iter()
+            .map(|only| Pattern::new(only).map_err(|err| Error::Config(format!("invalid config pattern, pattern={only}, err={err}")).into()))
+            .collect()
+    }
+
+    fn mode(&self) -> Mode {
+        if self.check {
+            Mode::Check
+        } else if self.dry_run {
+            Mode::DryRun
+        } else {
+            Mode::Apply
+        }
+    }
+}
+
+// pattern matches config name or file path relative to db dir, e.g. payments/orders, payments/* or orders.json
+fn name_matches(pattern: &Pattern, env_dir: &Path, path: &Path) -> bool {
+    pattern.matches(&config::db_config_name(env_dir, path)) || pattern.matches(&config::relative_path(&env_dir.join("db"), path))
 }
 
-async fn sync_db(config: &DBConfig, public_ip: &str) -> Result<()> {
+// pattern matches instance name or db type, db type is case insensitive, e.g. mysql
+fn config_matches(pattern: &Pattern, config: &DBConfig) -> bool {
+    let db_type = match config.db_type {
+        DBType::MySQL => "MySQL",
+        DBType::PostgreSQL => "PostgreSQL",
+    };
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+    pattern.matches(&config.instance) || pattern.matches_with(db_type, options)
+}
+
+// returns results and error which stops sync, error is only returned without keep going
+async fn sync_configs(
+    configs: Vec<LoadedConfig>,
+    env_dir: &Path,
+    mode: Mode,
+    prune: Option<Prune<'static>>,
+    selection: &Selection,
+    keep_going: bool,
+) -> (Vec<SyncResult>, Option<anyhow::Error>) {
+    let instance_configs: Vec<&DBConfig> = configs.iter().map(|loaded| &loaded.config).collect();
+    let prune = prune.map(|prune| Prune {
+        configs: &instance_configs,
+        ..prune
+    });
+    let mut results = vec![];
+    for LoadedConfig { path, config, .. } in configs.iter().filter(|loaded| loaded.selected) {
+        let name = config::db_config_name(env_dir, path);
+        let mut result = SyncResult::new(path);
+        let outcome = sync_config(&name, config, env_dir, mode, prune.as_ref(), selection, &mut result)
+            .instrument(info_span!("sync", config = name))
+            .await;
+        if let Err(err) = outcome {
+            if !keep_going {
+                return (
+                    results,
+                    Some(err.context(format!("failed to sync db, config={}", path.to_string_lossy()))),
+                );
+            }
+            error!(config = name, "failed to sync db, config={}, error={err:#}", path.to_string_lossy());
+            result.error = Some(format!("{err:#}"));
+        }
+        results.push(result);
+    }
+    (results, None)
+}
+
+async fn sync_config(
+    name: &str,
+    config: &DBConfig,
+    env_dir: &Path,
+    mode: Mode,
+    prune: Option<&Prune<'_>>,
+    selection: &Selection,
+    result: &mut SyncResult,
+) -> Result<()> {
+    info!("sync db config, project={}, instance={}", config.project, config.instance);
+    let instance = sql_admin::get_sql_instance(&config.project, &config.instance).await?;
+    let private_ip = instance.private_address()?;
+    sync_db(name, config, &instance, mode, prune, selection, result).await?;
+    if sync_kube_endpoints(name, config, env_dir, private_ip, mode)? {
+        result.drifted = true;
+        result.endpoint_written = mode == Mode::Apply;
+    }
+    Ok(())
+}
+
+// planned changes are recorded in result before applying
+async fn sync_db(
+    name: &str,
+    config: &DBConfig,
+    instance: &GetSQLInstanceResponse,
+    mode: Mode,
+    prune: Option<&Prune<'_>>,
+    selection: &Selection,
+    result: &mut SyncResult,
+) -> Result<()> {
+    let public_ip = instance.public_address()?;
     let root_user = match config.db_type {
         DBType::MySQL => "root",
         DBType::PostgreSQL => "postgres",
     };
-    let root_password = secret_manager::get_or_create(&config.project, &config.root_secret, &config.env).await;
-    sql_admin::set_password(&config.project, &config.instance, root_user, &root_password).await;
 
-    let mut database = Database::create_database(&config.db_type, public_ip, &root_password).await?;
-
-    for db in &config.dbs {
-        database.create_db(db).await?;
+    // root credential must be in place before live state can be read
+    let mut root_plan = Plan::default();
+    let root_password = password(&mut root_plan, config, &config.root_secret).await?;
+    let root_login = !root_password.generated && root_login(config, public_ip, root_user, &root_password.value).await?;
+    if !root_login {
+        root_plan.actions.push(Action::SetPassword {
+            project: config.project.to_owned(),
+            instance: config.instance.to_owned(),
+            user: root_user.to_owned(),
+            password: root_password.value.to_owned(),
+        });
+    }
+    print_plan(name, &root_plan, mode);
+    result.add_plan(&root_plan);
+    if mode == Mode::Apply {
+        root_plan.apply_actions().await?;
     }
 
-    for user in &config.users {
+    let mut database = if mode != Mode::Apply && !root_login {
+        info!("root password is not set, plan against empty instance");
+        None
+    } else {
+        Some(Database::create_database(&config.db_type, public_ip, &root_password.value).await?)
+    };
+    let state = match &mut database {
+        Some(database) => database.state(config).await?,
+        None => State::empty(&config.db_type),
+    };
+
+    let mut plan = Plan::default();
+    let mut passwords = HashMap::new();
+    for user in config.users.iter().filter(|user| selection.user(user)) {
         if let Auth::Password = user.auth {
-            let password = secret_manager::get_or_create(&config.project, user.secret.as_ref().unwrap(), &config.env).await;
-            database.create_user(user, &password).await?;
+            let mut password = password(&mut plan, config, user.secret.as_ref().unwrap()).await?;
+            // same as root, secret may be changed in secret manager, so existing user is checked by login with stored secret
+            if database.is_some() && !password.generated && state.has_user(&user.name) {
+                password.login_failed = user_login_failed(config, public_ip, &user.name, &password.value).await?;
+            }
+            passwords.insert(user.name.to_owned(), password);
         }
-        database.grant_user_privileges(user, &config.dbs).await?;
     }
+    iam_users(&mut plan, config, instance, selection).await?;
+    // mysql db user of iam group member is email without domain, same as iam user
+    let group_members: Vec<String> = match (prune, &config.db_type) {
+        (Some(_), DBType::MySQL) => sql_admin::list_iam_group_members(&config.project, &config.instance)
+            .await?
+            .iter()
+            .map(|member| member.split('@').next().unwrap().to_owned())
+            .collect(),
+        _ => vec![],
+    };
+    let prune = prune.map(|prune| Prune {
+        group_members: &group_members,
+        ..*prune
+    });
+    plan.changes = state.changes(config, &passwords, prune.as_ref(), selection);
+    print_plan(name, &plan, mode);
+    result.add_plan(&plan);
 
+    if let (Mode::Apply, Some(database)) = (mode, &mut database) {
+        plan.apply(database).await?;
+    }
     Ok(())
 }
 
-fn sync_kube_endpoints(config: &DBConfig, env_dir: &Path, private_ip: &str) {
-    let endpoint_path = env_dir.join(&config.endpoint.path);
-    info!(path = endpoint_path.to_str(), "write kube endpoint");
-    fs::create_dir_all(endpoint_path.parent().expect("endpoint should have parent dir")).unwrap_or_else(|err| panic!("{err}"));
+async fn root_login(config: &DBConfig, public_ip: &str, root_user: &str, password: &str) -> Result<bool> {
+    match Database::verify_login(&config.db_type, public_ip, root_user, password, "postgres").await {
+        Ok(()) => {
+            info!(user = root_user, "login with root secret succeeded, skip setting root password");
+            Ok(true)
+        }
+        Err(err) if db::auth_failed(&config.db_type, &err) => {
+            info!(
+                user = root_user,
+                "login with root secret failed, set root password via sql admin, error={err}"
+            );
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// only auth failure means password differs, other errors, e.g. locked user or no connect privilege, leave password as is
+async fn user_login_failed(config: &DBConfig, public_ip: &str, user: &str, password: &str) -> Result<bool> {
+    match Database::verify_login(&config.db_type, public_ip, user, password, "postgres").await {
+        Ok(()) => Ok(false),
+        Err(err) if db::auth_failed(&config.db_type, &err) => {
+            info!(user, "login with user secret failed, reset password, error={err}");
+            Ok(true)
+        }
+        Err(err) => {
+            warn!(user, "failed to verify login with user secret, skip password reset, error={err}");
+            Ok(false)
+        }
+    }
+}
+
+// iam users are registered via sql admin, which creates db user with derived name, grants are applied afterwards
+async fn iam_users(plan: &mut Plan, config: &DBConfig, instance: &GetSQLInstanceResponse, selection: &Selection) -> Result<()> {
+    let iam_users: Vec<&User> = config
+        .users
+        .iter()
+        .filter(|user| matches!(user.auth, Auth::Iam) && selection.user(user))
+        .collect();
+    if iam_users.is_empty() {
+        return Ok(());
+    }
+
+    // iam users can only login with iam authentication flag on
+    let flag = DatabaseFlag {
+        name: match config.db_type {
+            DBType::MySQL => "cloudsql_iam_authentication",
+            DBType::PostgreSQL => "cloudsql.iam_authentication",
+        }
+        .to_owned(),
+        value: "on".to_owned(),
+    };
+    let flags = &instance.settings.database_flags;
+    if !flags.contains(&flag) {
+        warn!(
+            instance = config.instance,
+            flag = flag.name,
+            "iam authentication flag is off, setting it may restart instance"
+        );
+        let mut flags: Vec<DatabaseFlag> = flags.iter().filter(|existing| existing.name != flag.name).cloned().collect();
+        flags.push(flag.clone());
+        plan.actions.push(Action::SetDatabaseFlag {
+            project: config.project.to_owned(),
+            instance: config.instance.to_owned(),
+            flag,
+            flags,
+        });
+    }
 
+    let sql_users = sql_admin::list_users(&config.project, &config.instance).await?;
+    for user in iam_users {
+        let name = user.iam_user(&config.db_type);
+        if !sql_users.iter().any(|sql_user| sql_user == name) {
+            plan.actions.push(Action::CreateIamUser {
+                project: config.project.to_owned(),
+                instance: config.instance.to_owned(),
+                user: name.to_owned(),
+                user_type: user.iam_type().sql_user_type().to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn print_plan(name: &str, plan: &Plan, mode: Mode) {
+    match mode {
+        Mode::Check => plan.print_diff(name),
+        _ => plan.print(name),
+    }
+}
+
+async fn password(plan: &mut Plan, config: &DBConfig, secret: &str) -> Result<Password> {
+    if let Some(value) = secret_manager::get(&config.project, secret).await? {
+        return Ok(Password {
+            value,
+            generated: false,
+            login_failed: false,
+        });
+    }
+    info!(secret, "secret not found, create new one");
+    let value = secret_manager::generate_password();
+    plan.actions.push(Action::CreateSecret {
+        project: config.project.to_owned(),
+        name: secret.to_owned(),
+        env: config.env.to_owned(),
+        value: value.to_owned(),
+    });
+    Ok(Password {
+        value,
+        generated: true,
+        login_failed: false,
+    })
+}
+
+// returns whether endpoint file is stale
+fn sync_kube_endpoints(name: &str, config: &DBConfig, env_dir: &Path, private_ip: &str, mode: Mode) -> Result<bool> {
+    let endpoint_path = env_dir.join(&config.endpoint.path);
     let contents = kube::endpoint::Endpoint {
         name: &config.endpoint.name,
         ns: &config.endpoint.ns,
         ip: private_ip,
     }
     .to_kube_config();
-    fs::write(endpoint_path, contents).unwrap_or_else(|err| panic!("{err}"));
-}
-
-fn db_config_paths(env_dir: &Path) -> Vec<PathBuf> {
-    let db_dir = env_dir.join("db");
 
-    if !db_dir.exists() {
-        panic!("db dir doesn't exist, dir={}", db_dir.to_string_lossy());
+    if fs::read_to_string(&endpoint_path).is_ok_and(|current| current == contents) {
+        return Ok(false);
+    }
+    match mode {
+        Mode::Check => println!("{name}: ~ endpoint {}", endpoint_path.to_string_lossy()),
+        _ => println!("{name}: kube: write endpoint, path={}", endpoint_path.to_string_lossy()),
+    }
+    if mode != Mode::Apply {
+        return Ok(true);
     }
 
-    let paths: Vec<PathBuf> = fs::read_dir(&db_dir)
-        .unwrap_or_else(|err| panic!("{err}"))
-        .flatten()
-        .filter(|entry| {
-            if let Some(file_name) = entry.file_name().to_str() {
-                return file_name.ends_with(".json");
-            }
-            false
-        })
-        .map(|entry| entry.path())
-        .collect();
-
-    paths
+    info!(path = endpoint_path.to_str(), "write kube endpoint");
+    if let Some(dir) = endpoint_path.parent() {
+        fs::create_dir_all(dir).map_err(Error::io(dir))?;
+    }
+    fs::write(&endpoint_path, contents).map_err(Error::io(&endpoint_path))?;
+    Ok(true)
 }
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::io;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::db::Change;
+use crate::db::Database;
+use crate::gcloud::secret_manager;
+use crate::gcloud::sql_admin;
+use crate::gcloud::sql_admin::DatabaseFlag;
+
+pub enum Action {
+    CreateSecret {
+        project: String,
+        name: String,
+        env: String,
+        value: String,
+    },
+    SetPassword {
+        project: String,
+        instance: String,
+        user: String,
+        password: String,
+    },
+    CreateIamUser {
+        project: String,
+        instance: String,
+        user: String,
+        user_type: String,
+    },
+    SetDatabaseFlag {
+        project: String,
+        instance: String,
+        flag: DatabaseFlag,
+        // all flags of instance including the one to set, as sql admin replaces whole list
+        flags: Vec<DatabaseFlag>,
+    },
+}
+
+#[derive(Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    pub changes: Vec<Change>,
+}
+
+impl Action {
+    async fn apply(&self) -> Result<()> {
+        match self {
+            Action::CreateSecret { project, name, env, value } => secret_manager::create(project, name, env, value).await?,
+            Action::SetPassword {
+                project,
+                instance,
+                user,
+                password,
+            } => sql_admin::set_password(project, instance, user, password).await?,
+            Action::CreateIamUser {
+                project,
+                instance,
+                user,
+                user_type,
+            } => sql_admin::create_iam_user(project, instance, user, user_type).await?,
+            Action::SetDatabaseFlag {
+                project, instance, flags, ..
+            } => sql_admin::set_database_flags(project, instance, flags).await?,
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CreateSecret { project, name, .. } => write!(f, "create secret, project={project}, secret={name}"),
+            Action::SetPassword { instance, user, .. } => write!(f, "set sql user password, instance={instance}, user={user}"),
+            Action::CreateIamUser {
+                instance, user, user_type, ..
+            } => write!(f, "create sql iam user, instance={instance}, user={user}, type={user_type}"),
+            Action::SetDatabaseFlag { instance, flag, .. } => write!(
+                f,
+                "set sql instance database flag, instance={instance}, flag={}={} (may restart instance)",
+                flag.name, flag.value
+            ),
+        }
+    }
+}
+
+impl Plan {
+    pub fn drifted(&self) -> bool {
+        !self.changes.is_empty() || !self.actions.is_empty()
+    }
+
+    // plans of configs are printed in parallel, each line is prefixed with config name,
+    // and stdout is locked so lines of one plan are not interleaved with others
+    pub fn print(&self, config: &str) {
+        let _stdout = io::stdout().lock();
+        for action in &self.actions {
+            println!("{config}: gcloud: {action}");
+        }
+        for change in &self.changes {
+            println!("{config}: {change}");
+            for statement in &change.statements {
+                println!("{config}:     {statement}");
+            }
+        }
+    }
+
+    pub fn print_diff(&self, config: &str) {
+        let _stdout = io::stdout().lock();
+        for action in &self.actions {
+            match action {
+                Action::CreateSecret { name, .. } => println!("{config}: + secret {name}"),
+                Action::SetPassword { user, .. } => println!("{config}: ~ password {user}"),
+                Action::CreateIamUser { user, .. } => println!("{config}: + iam user {user}"),
+                Action::SetDatabaseFlag { flag, .. } => println!("{config}: ~ database flag {}", flag.name),
+            }
+        }
+        for change in &self.changes {
+            println!("{config}: {change}");
+        }
+    }
+
+    pub async fn apply_actions(&self) -> Result<()> {
+        for action in &self.actions {
+            action.apply().await.with_context(|| format!("failed to {action}"))?;
+        }
+        Ok(())
+    }
+
+    pub async fn apply(&self, database: &mut Database) -> Result<()> {
+        self.apply_actions().await?;
+        for change in &self.changes {
+            database
+                .execute(&change.statements)
+                .await
+                .with_context(|| format!("failed to apply change, change={change}"))?;
+        }
+        Ok(())
+    }
+}
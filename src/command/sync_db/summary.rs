@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use crate::command::sync_db::plan::Action;
+use crate::command::sync_db::plan::Plan;
+use crate::db::ChangeKind;
+use crate::db::Object;
+
+// outcome of syncing one db config, changes are planned ones in dry run and check mode
+#[derive(Default)]
+pub struct SyncResult {
+    pub config: String,
+    pub created_dbs: usize,
+    pub created_users: usize,
+    pub created_roles: usize,
+    // existing dbs and users, e.g. db settings, password reset or unlock
+    pub altered: usize,
+    pub changed_grants: usize,
+    pub endpoint_written: bool,
+    pub drifted: bool,
+    pub error: Option<String>,
+}
+
+impl SyncResult {
+    pub fn new(path: &Path) -> Self {
+        SyncResult {
+            config: path.to_string_lossy().to_string(),
+            ..SyncResult::default()
+        }
+    }
+
+    pub fn add_plan(&mut self, plan: &Plan) {
+        self.drifted |= plan.drifted();
+        for action in &plan.actions {
+            if let Action::CreateIamUser { .. } = action {
+                self.created_users += 1;
+            }
+        }
+        for change in &plan.changes {
+            match (&change.kind, change.object) {
+                (_, Object::Grant) => self.changed_grants += 1,
+                (ChangeKind::Add, Object::DB) => self.created_dbs += 1,
+                (ChangeKind::Add, Object::User) => self.created_users += 1,
+                (ChangeKind::Add, Object::Role) => self.created_roles += 1,
+                (ChangeKind::Alter, _) => self.altered += 1,
+                (ChangeKind::Remove, _) => {}
+            }
+        }
+    }
+}
+
+pub fn print_summary(results: &[SyncResult]) {
+    let width = results
+        .iter()
+        .map(|result| result.config.len())
+        .max()
+        .unwrap_or_default()
+        .max("config".len());
+    println!();
+    println!(
+        "{:<width$}  {:>3}  {:>5}  {:>5}  {:>7}  {:>6}  {:<8}  result",
+        "config", "dbs", "users", "roles", "altered", "grants", "endpoint"
+    );
+    for result in results {
+        let endpoint = if result.endpoint_written { "written" } else { "-" };
+        let status = match &result.error {
+            Some(error) => format!("failed: {error}"),
+            None => "ok".to_owned(),
+        };
+        println!(
+            "{:<width$}  {:>3}  {:>5}  {:>5}  {:>7}  {:>6}  {:<8}  {status}",
+            result.config, result.created_dbs, result.created_users, result.created_roles, result.altered, result.changed_grants, endpoint
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::command::sync_db::plan::Plan;
+    use crate::command::sync_db::summary::SyncResult;
+    use crate::db::Change;
+    use crate::db::Object;
+
+    #[test]
+    fn count_planned_changes() {
+        let plan = Plan {
+            actions: vec![],
+            changes: vec![
+                Change::add(Object::DB, "orders".to_owned(), vec![]),
+                Change::alter(Object::DB, "payments".to_owned(), vec![]),
+                Change::add(Object::User, "app".to_owned(), vec![]),
+                Change::alter(Object::User, "migration (password)".to_owned(), vec![]),
+                Change::alter(Object::User, "viewer (unlock)".to_owned(), vec![]),
+                Change::add(Object::Role, "orders_readonly".to_owned(), vec![]),
+                Change::add(Object::Grant, "SELECT ON `orders`.* TO app".to_owned(), vec![]),
+                Change::remove(Object::Grant, "DELETE ON `orders`.* TO app".to_owned(), vec![]),
+                Change::remove(Object::User, "legacy".to_owned(), vec![]),
+            ],
+        };
+        let mut result = SyncResult::new(Path::new("db/orders.json"));
+        result.add_plan(&plan);
+
+        assert_eq!(result.created_dbs, 1);
+        assert_eq!(result.created_users, 1);
+        assert_eq!(result.created_roles, 1);
+        assert_eq!(result.altered, 3);
+        assert_eq!(result.changed_grants, 2);
+        assert!(result.drifted);
+    }
+}
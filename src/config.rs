@@ -1 +1,172 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use glob::MatchOptions;
+use glob::Pattern;
+
+use crate::config::defaults::DEFAULTS_NAME;
+use crate::error::Error;
+use crate::error::Result;
+
 pub mod db_config;
+pub mod defaults;
+pub mod format;
+
+const IGNORE_FILE: &str = ".gmignore";
+
+// configs are discovered recursively under db dir, e.g. db/payments/orders.json, and sorted by path for reproducible runs
+pub fn db_config_paths(env_dir: &Path) -> Result<Vec<PathBuf>> {
+    let db_dir = env_dir.join("db");
+
+    if !db_dir.exists() {
+        return Err(Error::Config(format!("db dir doesn't exist, dir={}", db_dir.to_string_lossy())));
+    }
+
+    let ignore = Ignore::load(&db_dir)?;
+    let mut paths = vec![];
+    collect_paths(&db_dir, &db_dir, &ignore, &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+fn collect_paths(db_dir: &Path, dir: &Path, ignore: &Ignore, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(Error::io(dir))?.flatten() {
+        let path = entry.path();
+        let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        // hidden files and editor temp files, e.g. .orders.json.swp, .#orders.json or #orders.json#
+        if file_name.starts_with('.') || file_name.starts_with('#') {
+            continue;
+        }
+        if dir == db_dir && path.file_stem().is_some_and(|stem| stem == DEFAULTS_NAME) {
+            continue;
+        }
+        let relative_path = relative_path(db_dir, &path);
+        let is_dir = path.is_dir();
+        if ignore.ignored(&relative_path, &file_name, is_dir) {
+            continue;
+        }
+        if is_dir {
+            collect_paths(db_dir, &path, ignore, paths)?;
+        } else if format::supported(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// config of name in any format, e.g. payments/orders for db/payments/orders.yaml
+pub fn db_config_path(env_dir: &Path, name: &str) -> Result<PathBuf> {
+    let paths: Vec<PathBuf> = format::EXTENSIONS
+        .iter()
+        .map(|extension| env_dir.join("db").join(format!("{name}.{extension}")))
+        .filter(|path| path.exists())
+        .collect();
+    match paths.as_slice() {
+        [path] => Ok(path.to_owned()),
+        [] => Err(Error::Config(format!(
+            "db config doesn't exist, config={name}, dir={}",
+            env_dir.join("db").to_string_lossy()
+        ))),
+        _ => Err(Error::Config(format!(
+            "db config exists in multiple formats, config={name}, dir={}",
+            env_dir.join("db").to_string_lossy()
+        ))),
+    }
+}
+
+// config name is path relative to db dir without extension, e.g. payments/orders for db/payments/orders.json
+pub fn db_config_name(env_dir: &Path, path: &Path) -> String {
+    relative_path(&env_dir.join("db"), &path.with_extension(""))
+}
+
+// with '/' as separator on all platforms, as used in patterns
+pub fn relative_path(db_dir: &Path, path: &Path) -> String {
+    let relative_path = path.strip_prefix(db_dir).unwrap_or(path);
+    relative_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// patterns of db/.gmignore, one glob per line, same as .gitignore,
+// pattern with '/' matches path relative to db dir, otherwise file or dir name at any level, trailing '/' only matches dirs
+struct Ignore {
+    patterns: Vec<IgnorePattern>,
+}
+
+struct IgnorePattern {
+    pattern: Pattern,
+    // leading '/' anchors pattern to db dir, e.g. /orders.json
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Ignore {
+    fn load(db_dir: &Path) -> Result<Self> {
+        let path = db_dir.join(IGNORE_FILE);
+        if !path.exists() {
+            return Ok(Ignore { patterns: vec![] });
+        }
+        let content = fs::read_to_string(&path).map_err(Error::io(&path))?;
+        Self::parse(&content).map_err(|message| Error::Config(format!("{message}, path={}", path.to_string_lossy())))
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, String> {
+        let mut patterns = vec![];
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            let pattern = Pattern::new(line.trim_start_matches('/')).map_err(|err| format!("invalid ignore pattern, pattern={line}, err={err}"))?;
+            patterns.push(IgnorePattern { pattern, anchored, dir_only });
+        }
+        Ok(Ignore { patterns })
+    }
+
+    fn ignored(&self, relative_path: &str, file_name: &str, is_dir: bool) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.patterns.iter().any(|pattern| {
+            let target = if pattern.anchored { relative_path } else { file_name };
+            (is_dir || !pattern.dir_only) && pattern.pattern.matches_with(target, options)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::config::Ignore;
+    use crate::config::db_config_name;
+
+    #[test]
+    fn ignore_patterns() {
+        let ignore = Ignore::parse("# archived configs\narchive/\n/payments/legacy-*.json\n*.bak.json\n/tmp.json\n").unwrap();
+        assert!(ignore.ignored("archive", "archive", true));
+        assert!(!ignore.ignored("archive.json", "archive.json", false));
+        assert!(ignore.ignored("payments/legacy-orders.json", "legacy-orders.json", false));
+        assert!(!ignore.ignored("legacy-orders.json", "legacy-orders.json", false));
+        assert!(ignore.ignored("payments/orders.bak.json", "orders.bak.json", false));
+        assert!(!ignore.ignored("payments/orders.json", "orders.json", false));
+        assert!(!ignore.ignored("payments/team/legacy-orders.json", "legacy-orders.json", false));
+        assert!(ignore.ignored("tmp.json", "tmp.json", false));
+        assert!(!ignore.ignored("payments/tmp.json", "tmp.json", false));
+    }
+
+    #[test]
+    fn config_name() {
+        let env_dir = Path::new("env/prod");
+        assert_eq!(db_config_name(env_dir, Path::new("env/prod/db/orders.json")), "orders");
+        assert_eq!(db_config_name(env_dir, Path::new("env/prod/db/payments/orders.json")), "payments/orders");
+    }
+}
@@ -1,6 +1,15 @@
+use std::fs;
+use std::path::Path;
+
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-#[derive(Deserialize, Debug)]
+use crate::config::defaults::Defaults;
+use crate::config::format;
+use crate::error::Error;
+use crate::error::Result;
+
+#[derive(Deserialize, JsonSchema, Debug)]
 pub struct DBConfig {
     pub version: String,
     pub project: String,
@@ -15,48 +24,112 @@ pub struct DBConfig {
     pub endpoint: Endpoint,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 pub enum DBType {
     MySQL,
     PostgreSQL,
 }
 
 impl DBConfig {
-    pub fn validate(&self) {
+    // validated after defaults are applied, so required fields may come from defaults
+    pub fn load(path: &Path, defaults: &Defaults) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(Error::io(path))?;
+        let config =
+            format::parse(path, &content).map_err(|err| Error::Config(format!("failed to parse db config, path={}, err={err}", path.display())))?;
+        let config = defaults
+            .apply(config)
+            .map_err(|message| Error::Config(format!("{message}, path={}", path.display())))?;
+        // only syntax errors have line and column, type errors are found after merged with defaults, which has no position,
+        // so field path is included instead, e.g. users[1].role, field may be inherited from defaults
+        let config: DBConfig = serde_path_to_error::deserialize(config).map_err(|err| {
+            Error::Config(format!(
+                "failed to parse db config, path={}, field={}, err={}",
+                path.display(),
+                err.path(),
+                err.inner()
+            ))
+        })?;
+        config
+            .validate()
+            .map_err(|message| Error::Config(format!("{message}, path={}", path.display())))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
         let version = env!("CARGO_PKG_VERSION");
         let config_version = &self.version;
         if config_version != version {
-            panic!("config version does not match gm, config_version={config_version}, gm_version={version}");
+            return Err(format!(
+                "config version does not match gm, config_version={config_version}, gm_version={version}"
+            ));
         }
 
         for user in &self.users {
-            if matches!(self.db_type, DBType::MySQL) && user.name.len() > 32 {
-                panic!("db user name must be no longer than 32, user={}", user.name);
+            if let (Auth::Iam, false) = (&user.auth, user.name.contains('@')) {
+                return Err(format!("db iam user name must be email, user={}", user.name));
+            }
+            if matches!(self.db_type, DBType::MySQL) && user.db_user(&self.db_type).len() > 32 {
+                return Err(format!("db user name must be no longer than 32, user={}", user.name));
             }
             if let (Auth::Password, None) = (&user.auth, &user.secret) {
-                panic!("db password user must have secret, user={}", user.name);
+                return Err(format!("db password user must have secret, user={}", user.name));
             }
         }
+        Ok(())
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 pub struct User {
     pub name: String,
     pub auth: Auth,
     pub secret: Option<String>,
     pub db: Option<String>,
     pub role: Role,
+    // only for IAM users, default to SERVICE_ACCOUNT if email ends with .gserviceaccount.com, otherwise USER
+    #[serde(rename(deserialize = "iamType"))]
+    pub iam_type: Option<IamType>,
 }
 
-#[derive(Deserialize, Debug)]
+impl User {
+    // name of IAM user is email, db user name is derived by cloud sql
+    pub fn db_user(&self, db_type: &DBType) -> &str {
+        let Auth::Iam = self.auth else {
+            return &self.name;
+        };
+        match (db_type, self.iam_type()) {
+            (_, IamType::Group) => &self.name,
+            (DBType::MySQL, _) => self.name.split('@').next().unwrap(),
+            (DBType::PostgreSQL, IamType::ServiceAccount) => self.name.strip_suffix(".gserviceaccount.com").unwrap_or(&self.name),
+            (DBType::PostgreSQL, IamType::User) => &self.name,
+        }
+    }
+
+    // name registered via sql admin api, postgres expects service account without .gserviceaccount.com suffix, mysql expects full email
+    pub fn iam_user(&self, db_type: &DBType) -> &str {
+        match db_type {
+            DBType::MySQL => &self.name,
+            DBType::PostgreSQL => self.db_user(db_type),
+        }
+    }
+
+    pub fn iam_type(&self) -> IamType {
+        match self.iam_type {
+            Some(iam_type) => iam_type,
+            None if self.name.ends_with(".gserviceaccount.com") => IamType::ServiceAccount,
+            None => IamType::User,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
 pub struct Endpoint {
     pub name: String,
     pub ns: String,
     pub path: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug)]
 pub enum Auth {
     #[serde(rename(deserialize = "IAM"))]
     Iam,
@@ -64,7 +137,28 @@ pub enum Auth {
     Password,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy)]
+pub enum IamType {
+    #[serde(rename(deserialize = "USER"))]
+    User,
+    #[serde(rename(deserialize = "SERVICE_ACCOUNT"))]
+    ServiceAccount,
+    #[serde(rename(deserialize = "GROUP"))]
+    Group,
+}
+
+impl IamType {
+    // user type of sql admin api
+    pub fn sql_user_type(&self) -> &'static str {
+        match self {
+            IamType::User => "CLOUD_IAM_USER",
+            IamType::ServiceAccount => "CLOUD_IAM_SERVICE_ACCOUNT",
+            IamType::Group => "CLOUD_IAM_GROUP",
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug)]
 pub enum Role {
     #[serde(rename(deserialize = "APP"))]
     App,
@@ -75,3 +169,27 @@ pub enum Role {
     #[serde(rename(deserialize = "REPLICATION"))]
     Replication,
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::db_config::DBType;
+    use crate::config::db_config::User;
+    use crate::util::json;
+
+    #[test]
+    fn derive_iam_db_user() {
+        let service_account: User = json::from_json(r#"{"name": "app@project.iam.gserviceaccount.com", "auth": "IAM", "role": "APP"}"#).unwrap();
+        assert_eq!(service_account.db_user(&DBType::PostgreSQL), "app@project.iam");
+        assert_eq!(service_account.iam_user(&DBType::PostgreSQL), "app@project.iam");
+        assert_eq!(service_account.db_user(&DBType::MySQL), "app");
+        assert_eq!(service_account.iam_user(&DBType::MySQL), "app@project.iam.gserviceaccount.com");
+
+        let user: User = json::from_json(r#"{"name": "dev@example.com", "auth": "IAM", "role": "VIEWER"}"#).unwrap();
+        assert_eq!(user.db_user(&DBType::PostgreSQL), "dev@example.com");
+        assert_eq!(user.db_user(&DBType::MySQL), "dev");
+
+        let group: User = json::from_json(r#"{"name": "devs@example.com", "auth": "IAM", "role": "VIEWER", "iamType": "GROUP"}"#).unwrap();
+        assert_eq!(group.db_user(&DBType::MySQL), "devs@example.com");
+        assert_eq!(group.iam_type().sql_user_type(), "CLOUD_IAM_GROUP");
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::config::format;
+use crate::config::format::EXTENSIONS;
+use crate::error::Error;
+use crate::error::Result;
+
+// env.json in env dir, or _defaults.json under db dir, which is not a db config, either may be in any config format
+const ENV_NAME: &str = "env";
+pub const DEFAULTS_NAME: &str = "_defaults";
+
+const TEMPLATE_FIELDS: [&str; 3] = ["project", "env", "instance"];
+
+// fields shared by all db configs of env, e.g. project, env and version, fields of db config override defaults,
+// inherited strings are templates, {project}, {env} and {instance} are replaced with values of db config, e.g. "rootSecret": "{instance}-root"
+#[derive(Default)]
+pub struct Defaults {
+    fields: Map<String, Value>,
+}
+
+impl Defaults {
+    // from either env.json or db/_defaults.json, defaults are optional
+    pub fn load(env_dir: &Path) -> Result<Self> {
+        let paths: Vec<PathBuf> = EXTENSIONS
+            .iter()
+            .flat_map(|extension| {
+                [
+                    env_dir.join(format!("{ENV_NAME}.{extension}")),
+                    env_dir.join("db").join(format!("{DEFAULTS_NAME}.{extension}")),
+                ]
+            })
+            .filter(|path| path.exists())
+            .collect();
+        let path = match paths.as_slice() {
+            [] => return Ok(Defaults::default()),
+            [path] => path,
+            _ => {
+                return Err(Error::Config(format!(
+                    "only one defaults file is allowed, dir={}, files={}",
+                    env_dir.to_string_lossy(),
+                    paths.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>().join(",")
+                )));
+            }
+        };
+
+        let content = fs::read_to_string(path).map_err(Error::io(path))?;
+        let Value::Object(fields) = format::parse(path, &content)
+            .map_err(|err| Error::Config(format!("failed to parse defaults, path={}, err={err}", path.to_string_lossy())))?
+        else {
+            return Err(Error::Config(format!("defaults must be object, path={}", path.to_string_lossy())));
+        };
+        Ok(Defaults { fields })
+    }
+
+    pub fn apply(&self, config: Value) -> std::result::Result<Value, String> {
+        let Value::Object(mut config) = config else {
+            return Err("db config must be object".to_owned());
+        };
+
+        let mut variables = vec![];
+        for field in TEMPLATE_FIELDS {
+            if let Some(Value::String(value)) = config.get(field).or_else(|| self.fields.get(field)) {
+                variables.push((format!("{{{field}}}"), value.to_owned()));
+            }
+        }
+        merge(&mut config, &self.fields, &variables);
+        Ok(Value::Object(config))
+    }
+}
+
+// objects are merged field by field, other values of db config replace defaults
+fn merge(config: &mut Map<String, Value>, defaults: &Map<String, Value>, variables: &[(String, String)]) {
+    for (key, default) in defaults {
+        match (config.get_mut(key), default) {
+            (Some(Value::Object(config)), Value::Object(defaults)) => merge(config, defaults, variables),
+            (Some(_), _) => {}
+            (None, _) => {
+                config.insert(key.to_owned(), render(default, variables));
+            }
+        }
+    }
+}
+
+fn render(value: &Value, variables: &[(String, String)]) -> Value {
+    match value {
+        Value::String(template) => Value::String(
+            variables
+                .iter()
+                .fold(template.to_owned(), |value, (name, replacement)| value.replace(name, replacement)),
+        ),
+        Value::Object(fields) => Value::Object(fields.iter().map(|(key, value)| (key.to_owned(), render(value, variables))).collect()),
+        Value::Array(values) => Value::Array(values.iter().map(|value| render(value, variables)).collect()),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::config::defaults::Defaults;
+
+    #[test]
+    fn apply_defaults() {
+        let defaults = Defaults {
+            fields: json!({"project": "project", "env": "prod", "rootSecret": "{instance}-root", "endpoint": {"ns": "{env}", "path": "kube/{instance}.yaml"}})
+                .as_object()
+                .unwrap()
+                .to_owned(),
+        };
+
+        let config = defaults
+            .apply(json!({"env": "staging", "instance": "orders-db", "endpoint": {"name": "orders-db", "ns": "db"}}))
+            .unwrap();
+
+        assert_eq!(
+            config,
+            json!({"project": "project", "env": "staging", "instance": "orders-db", "rootSecret": "orders-db-root",
+                "endpoint": {"name": "orders-db", "ns": "db", "path": "kube/orders-db.yaml"}})
+        );
+    }
+}
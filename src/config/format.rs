@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+// format of config is picked by file extension
+pub const EXTENSIONS: [&str; 4] = ["json", "yaml", "yml", "toml"];
+
+pub fn supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension))
+}
+
+// syntax error contains line and column, but not content, config is parsed into json value, so defaults are merged the same way for all formats,
+// type errors, e.g. "role": 1, are only found when deserializing merged config, which reports field path instead of position
+pub fn parse(path: &Path, content: &str) -> Result<Value, String> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(content).map_err(|err| err.to_string()),
+        Some("yaml" | "yml") => serde_yaml_ng::from_str(content).map_err(|err| err.to_string()),
+        Some("toml") => toml::from_str(content).map_err(|err| {
+            // message may span multiple lines, e.g. "invalid array\nexpected `]`"
+            let message = err.message().trim().replace('\n', ", ");
+            let Some(span) = err.span() else {
+                return message;
+            };
+            let (line, column) = line_column(content, span.start);
+            format!("{message} at line {line} column {column}")
+        }),
+        _ => Err(format!("unsupported config format, supported={}", EXTENSIONS.join(","))),
+    }
+}
+
+// both are 1-based, same as json and yaml errors
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use crate::config::format::parse;
+
+    #[test]
+    fn parse_by_extension() {
+        let expected = json!({"instance": "orders-db", "dbs": ["orders"]});
+        assert_eq!(
+            parse(Path::new("orders.json"), r#"{"instance": "orders-db", "dbs": ["orders"]}"#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(Path::new("orders.yaml"), "# comment\ninstance: orders-db\ndbs:\n  - orders\n").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(Path::new("orders.toml"), "# comment\ninstance = \"orders-db\"\ndbs = [\"orders\"]\n").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_error_with_line_and_column() {
+        let err = parse(Path::new("orders.json"), "{\n  \"instance\": orders-db\n}").unwrap_err();
+        assert!(err.ends_with("at line 2 column 15"), "{err}");
+        let err = parse(Path::new("orders.yaml"), "instance: orders-db\n  dbs: [orders]\n").unwrap_err();
+        assert!(err.contains("at line 2 column 6"), "{err}");
+        let err = parse(Path::new("orders.toml"), "instance = \"orders-db\"\ndbs = [orders]\n").unwrap_err();
+        assert!(err.ends_with("at line 2 column 8"), "{err}");
+    }
+}
@@ -1,9 +1,17 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+
+use clap::ValueEnum;
 use mysql::MySQL;
 use postgresql::PostgreSQL;
 
+use crate::config::db_config::Auth;
+use crate::config::db_config::DBConfig;
 use crate::config::db_config::DBType;
+use crate::config::db_config::IamType;
 use crate::config::db_config::User;
+use crate::error::Error;
+use crate::error::Result;
 
 mod mysql;
 mod postgresql;
@@ -13,6 +21,74 @@ pub enum Database {
     PostgreSQL(PostgreSQL),
 }
 
+pub enum State {
+    MySQL(mysql::State),
+    PostgreSQL(postgresql::State),
+}
+
+// users and dbs not in any config of instance, excluding built-in ones,
+// configs must include all configs of instance, otherwise users and dbs of other configs are pruned
+#[derive(Clone, Copy)]
+pub struct Prune<'a> {
+    pub users: PruneUser,
+    pub dbs: bool,
+    pub configs: &'a [&'a DBConfig],
+    // db users created by cloud sql for members of iam groups, never in config, only listed for mysql,
+    // postgres members are found by membership of group role
+    pub group_members: &'a [String],
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum PruneUser {
+    Lock,
+    Drop,
+}
+
+// users and dbs of config to sync, all are synced if empty, grants outside selected dbs are left as is
+#[derive(Default)]
+pub struct Selection {
+    pub users: Vec<String>,
+    pub dbs: Vec<String>,
+}
+
+pub struct Password {
+    pub value: String,
+    // newly generated password must be applied even if user exists
+    pub generated: bool,
+    // login of existing user with stored secret failed, e.g. secret changed in secret manager, so password must be reset
+    pub login_failed: bool,
+}
+
+pub struct Change {
+    pub kind: ChangeKind,
+    pub object: Object,
+    // name of object, e.g. db name, or grant with grantee, e.g. "SELECT ON `orders`.* TO app"
+    pub target: String,
+    pub statements: Vec<Statement>,
+}
+
+pub enum ChangeKind {
+    // object is created or granted
+    Add,
+    // existing object is changed, e.g. password reset, user unlocked or db settings
+    Alter,
+    Remove,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Object {
+    DB,
+    User,
+    Role,
+    Grant,
+}
+
+pub struct Statement {
+    pub db: Option<String>,
+    pub sql: String,
+    secret: Option<String>,
+}
+
 impl Database {
     pub async fn create_database(db_type: &DBType, public_ip: &str, password: &str) -> Result<Database> {
         match db_type {
@@ -21,24 +97,189 @@ impl Database {
         }
     }
 
-    pub async fn create_db(&mut self, db: &str) -> Result<()> {
+    // db is only used by postgres, as postgres connection must be made to a database
+    pub async fn verify_login(db_type: &DBType, public_ip: &str, user: &str, password: &str, db: &str) -> Result<()> {
+        match db_type {
+            DBType::MySQL => MySQL::new(public_ip, user, password).await?.ping().await,
+            DBType::PostgreSQL => PostgreSQL::new(public_ip, user, password).await?.ping(db).await,
+        }
+    }
+
+    pub async fn change_password(&mut self, user: &str, password: &str) -> Result<()> {
+        match self {
+            Database::MySQL(mysql) => mysql.execute(&[mysql::alter_password_statement(user, password)]).await,
+            Database::PostgreSQL(postgresql) => postgresql.execute(&[postgresql::alter_password_statement(user, password)]).await,
+        }
+    }
+
+    pub async fn state(&mut self, config: &DBConfig) -> Result<State> {
+        match self {
+            Database::MySQL(mysql) => Ok(State::MySQL(mysql.state().await?)),
+            Database::PostgreSQL(postgresql) => Ok(State::PostgreSQL(postgresql.state(config).await?)),
+        }
+    }
+
+    pub async fn execute(&mut self, statements: &[Statement]) -> Result<()> {
+        match self {
+            Database::MySQL(mysql) => mysql.execute(statements).await,
+            Database::PostgreSQL(postgresql) => postgresql.execute(statements).await,
+        }
+    }
+}
+
+// login failed due to invalid password or missing user, sqlstate is "28000" on mysql and "28P01" on postgres,
+// "28000" on postgres means login is rejected for other reasons, e.g. role without login or no pg_hba.conf entry
+pub fn auth_failed(db_type: &DBType, err: &Error) -> bool {
+    let Error::Database(sqlx::Error::Database(err)) = err else {
+        return false;
+    };
+    match db_type {
+        DBType::MySQL => err.code().as_deref() == Some("28000"),
+        DBType::PostgreSQL => err.code().as_deref() == Some("28P01"),
+    }
+}
+
+impl State {
+    pub fn empty(db_type: &DBType) -> State {
+        match db_type {
+            DBType::MySQL => State::MySQL(mysql::State::default()),
+            DBType::PostgreSQL => State::PostgreSQL(postgresql::State::default()),
+        }
+    }
+
+    pub fn has_user(&self, user: &str) -> bool {
         match self {
-            Database::MySQL(mysql) => mysql.create_db(db).await,
-            Database::PostgreSQL(postgresql) => postgresql.create_db(db).await,
+            State::MySQL(state) => state.has_user(user),
+            State::PostgreSQL(state) => state.has_user(user),
         }
     }
 
-    pub async fn create_user(&mut self, user: &User, password: &str) -> Result<()> {
+    // passwords are keyed by user name, only PASSWORD users have one
+    pub fn changes(&self, config: &DBConfig, passwords: &HashMap<String, Password>, prune: Option<&Prune>, selection: &Selection) -> Vec<Change> {
         match self {
-            Database::MySQL(mysql) => mysql.create_user(&user.name, password).await,
-            Database::PostgreSQL(postgresql) => postgresql.create_user(user, password).await,
+            State::MySQL(state) => mysql::changes(config, state, passwords, prune, selection),
+            State::PostgreSQL(state) => postgresql::changes(config, state, passwords, prune, selection),
         }
     }
+}
+
+impl Prune<'_> {
+    pub fn in_config_db(&self, db: &str) -> bool {
+        self.configs.iter().any(|config| config.dbs.iter().any(|config_db| config_db == db))
+    }
+
+    pub fn in_config_user(&self, user: &str) -> bool {
+        self.configs
+            .iter()
+            .any(|config| config.users.iter().any(|config_user| config_user.db_user(&config.db_type) == user))
+    }
+
+    // iam group users, whose members are kept
+    pub fn in_config_group(&self, role: &str) -> bool {
+        self.configs.iter().any(|config| {
+            config
+                .users
+                .iter()
+                .any(|user| matches!(user.auth, Auth::Iam) && matches!(user.iam_type(), IamType::Group) && user.db_user(&config.db_type) == role)
+        })
+    }
+
+    pub fn config_dbs(&self) -> impl Iterator<Item = &String> {
+        self.configs.iter().flat_map(|config| &config.dbs)
+    }
+}
+
+impl Password {
+    pub fn reset(&self) -> bool {
+        self.generated || self.login_failed
+    }
+}
+
+impl Selection {
+    // IAM users are selected by email, same as name in config
+    pub fn user(&self, user: &User) -> bool {
+        self.users.is_empty() || self.users.contains(&user.name)
+    }
 
-    pub async fn grant_user_privileges(&mut self, user: &User, dbs: &[String]) -> Result<()> {
+    pub fn db(&self, db: &str) -> bool {
+        self.dbs.is_empty() || self.dbs.iter().any(|selected| selected == db)
+    }
+}
+
+impl Change {
+    pub fn add(object: Object, target: String, statements: Vec<Statement>) -> Self {
+        Change {
+            kind: ChangeKind::Add,
+            object,
+            target,
+            statements,
+        }
+    }
+
+    pub fn alter(object: Object, target: String, statements: Vec<Statement>) -> Self {
+        Change {
+            kind: ChangeKind::Alter,
+            object,
+            target,
+            statements,
+        }
+    }
+
+    pub fn remove(object: Object, target: String, statements: Vec<Statement>) -> Self {
+        Change {
+            kind: ChangeKind::Remove,
+            object,
+            target,
+            statements,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ChangeKind::Add => write!(f, "+ {} {}", self.object, self.target),
+            ChangeKind::Alter => write!(f, "~ {} {}", self.object, self.target),
+            ChangeKind::Remove => write!(f, "- {} {}", self.object, self.target),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Database::MySQL(mysql) => mysql.grant_user_privileges(user, dbs).await,
-            Database::PostgreSQL(postgresql) => postgresql.grant_user_privileges(user, dbs).await,
+            Object::DB => write!(f, "db"),
+            Object::User => write!(f, "user"),
+            Object::Role => write!(f, "role"),
+            Object::Grant => write!(f, "grant"),
+        }
+    }
+}
+
+impl Statement {
+    pub fn new(db: Option<&str>, sql: String) -> Self {
+        Statement {
+            db: db.map(str::to_owned),
+            sql,
+            secret: None,
+        }
+    }
+
+    // secret is masked when printing the statement
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(db) = &self.db {
+            write!(f, "[{db}] ")?;
+        }
+        match &self.secret {
+            Some(secret) => write!(f, "{}", self.sql.replace(secret, "******")),
+            None => write!(f, "{}", self.sql),
         }
     }
 }
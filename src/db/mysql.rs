@@ -1,16 +1,45 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
 use sqlx::MySql;
 use sqlx::Pool;
 use sqlx::mysql::MySqlConnectOptions;
 use tracing::info;
+use tracing::warn;
 
+use crate::config::db_config::DBConfig;
 use crate::config::db_config::Role;
 use crate::config::db_config::User;
+use crate::db::Change;
+use crate::db::Object;
+use crate::db::Password;
+use crate::db::Prune;
+use crate::db::PruneUser;
+use crate::db::Selection;
+use crate::db::Statement;
+use crate::error::Result;
+
+const SYSTEM_DBS: [&str; 4] = ["information_schema", "mysql", "performance_schema", "sys"];
 
 pub struct MySQL {
     pool: Pool<MySql>,
 }
 
+#[derive(Default)]
+pub struct State {
+    dbs: HashSet<String>,
+    users: HashSet<String>,
+    locked_users: HashSet<String>,
+    grants: HashMap<String, HashSet<Grant>>,
+}
+
+// scope is either "*.*" or "`db`.*"
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Grant {
+    scope: String,
+    privilege: String,
+}
+
 impl MySQL {
     pub async fn new(public_ip: &str, user: &str, password: &str) -> Result<Self> {
         let options = format!("mysql://{public_ip}")
@@ -21,47 +50,361 @@ impl MySQL {
         Ok(MySQL { pool })
     }
 
-    pub async fn create_db(&mut self, db: &str) -> Result<()> {
-        info!(db, "create db");
-        let statement = format!("CREATE DATABASE IF NOT EXISTS `{db}` CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci");
-        sqlx::query(&statement).execute(&self.pool).await?;
-        Ok(())
+    pub async fn state(&mut self) -> Result<State> {
+        info!("read mysql state");
+        let mut state = State::default();
+
+        let dbs: Vec<String> = sqlx::query_scalar("SELECT SCHEMA_NAME FROM information_schema.SCHEMATA")
+            .fetch_all(&self.pool)
+            .await?;
+        state.dbs.extend(dbs);
+
+        let users: Vec<String> = sqlx::query_scalar("SELECT User FROM mysql.user WHERE Host = '%'")
+            .fetch_all(&self.pool)
+            .await?;
+        state.users.extend(users);
+
+        let locked_users: Vec<String> = sqlx::query_scalar("SELECT User FROM mysql.user WHERE Host = '%' AND account_locked = 'Y'")
+            .fetch_all(&self.pool)
+            .await?;
+        state.locked_users.extend(locked_users);
+
+        let global_privileges: Vec<(String, String)> = sqlx::query_as("SELECT GRANTEE, PRIVILEGE_TYPE FROM information_schema.USER_PRIVILEGES")
+            .fetch_all(&self.pool)
+            .await?;
+        for (grantee, privilege) in global_privileges {
+            state.add_grant(&grantee, "*.*".to_owned(), privilege);
+        }
+
+        let schema_privileges: Vec<(String, String, String)> =
+            sqlx::query_as("SELECT GRANTEE, TABLE_SCHEMA, PRIVILEGE_TYPE FROM information_schema.SCHEMA_PRIVILEGES")
+                .fetch_all(&self.pool)
+                .await?;
+        for (grantee, db, privilege) in schema_privileges {
+            state.add_grant(&grantee, format!("`{db}`.*"), privilege);
+        }
+
+        Ok(state)
     }
 
-    pub async fn create_user(&mut self, user: &str, password: &str) -> Result<()> {
-        info!(user, "create user");
-        let statement = format!("CREATE USER IF NOT EXISTS '{user}'@'%'");
-        sqlx::query(&statement).execute(&self.pool).await?;
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
 
-        let statement = format!("ALTER USER '{user}'@'%' IDENTIFIED BY '{password}'");
-        sqlx::query(&statement).execute(&self.pool).await?;
+    pub async fn execute(&mut self, statements: &[Statement]) -> Result<()> {
+        for statement in statements {
+            info!(statement = statement.to_string(), "execute SQL");
+            sqlx::query(&statement.sql).execute(&self.pool).await?;
+        }
         Ok(())
     }
+}
+
+impl State {
+    pub fn has_user(&self, user: &str) -> bool {
+        self.users.contains(user)
+    }
+
+    fn add_grant(&mut self, grantee: &str, scope: String, privilege: String) {
+        // USAGE means no privileges
+        if privilege == "USAGE" {
+            return;
+        }
+        if let Some(user) = grantee_user(grantee) {
+            self.grants.entry(user.to_owned()).or_default().insert(Grant { scope, privilege });
+        }
+    }
+}
+
+pub fn changes(config: &DBConfig, state: &State, passwords: &HashMap<String, Password>, prune: Option<&Prune>, selection: &Selection) -> Vec<Change> {
+    let mut changes = vec![];
+    let users: Vec<&User> = config.users.iter().filter(|user| selection.user(user)).collect();
 
-    pub async fn grant_user_privileges(&mut self, user: &User, dbs: &[String]) -> Result<()> {
-        let privileges = match user.role {
-            Role::App => "SELECT, INSERT, UPDATE, DELETE",
-            Role::Migration => "CREATE, DROP, INDEX, ALTER, EXECUTE, SELECT, INSERT, UPDATE, DELETE",
-            Role::Viewer => "SELECT",
-            Role::Replication => "REPLICATION SLAVE, SELECT, RELOAD, REPLICATION CLIENT, LOCK TABLES, EXECUTE",
+    for db in config.dbs.iter().filter(|db| selection.db(db)) {
+        if !state.dbs.contains(db) {
+            let statement = format!("CREATE DATABASE IF NOT EXISTS `{db}` CHARACTER SET utf8mb4 COLLATE utf8mb4_unicode_ci");
+            changes.push(Change::add(Object::DB, db.to_owned(), vec![Statement::new(None, statement)]));
+        }
+    }
+
+    for user in &users {
+        let Some(password) = passwords.get(&user.name) else {
+            continue;
+        };
+        let (user_name, value) = (&user.name, &password.value);
+        let change = if !state.users.contains(user_name) {
+            let statement = format!("CREATE USER IF NOT EXISTS '{user_name}'@'%' IDENTIFIED BY '{value}'");
+            Change::add(
+                Object::User,
+                user_name.to_owned(),
+                vec![Statement::new(None, statement).with_secret(value)],
+            )
+        } else if password.reset() {
+            Change::alter(
+                Object::User,
+                format!("{user_name} (password)"),
+                vec![alter_password_statement(user_name, value)],
+            )
+        } else {
+            continue;
         };
+        changes.push(change);
+    }
+
+    for user in &users {
+        let user_name = user.db_user(&config.db_type);
+        if state.locked_users.contains(user_name) {
+            let statement = format!("ALTER USER '{user_name}'@'%' ACCOUNT UNLOCK");
+            changes.push(Change::alter(
+                Object::User,
+                format!("{user_name} (unlock)"),
+                vec![Statement::new(None, statement)],
+            ));
+        }
+    }
+
+    for user in &users {
+        let user_name = user.db_user(&config.db_type);
+        let desired: Vec<Grant> = grants(user, &config.dbs).into_iter().filter(|grant| grant.selected(selection)).collect();
+        let current = state.grants.get(user_name);
 
-        info!(user = user.name, privileges, "grant user privileges");
+        let missing = desired.iter().filter(|grant| !current.is_some_and(|current| current.contains(grant)));
+        for (scope, privileges) in group_by_scope(missing) {
+            let statement = format!("GRANT {privileges} ON {scope} TO '{user_name}'@'%'");
+            changes.push(Change::add(
+                Object::Grant,
+                format!("{privileges} ON {scope} TO {user_name}"),
+                vec![Statement::new(None, statement)],
+            ));
+        }
+
+        let mut extra: Vec<&Grant> = current
+            .iter()
+            .flat_map(|current| current.iter())
+            .filter(|grant| !desired.contains(grant) && grant.selected(selection))
+            .collect();
+        extra.sort();
+        for (scope, privileges) in group_by_scope(extra.into_iter()) {
+            let statement = format!("REVOKE {privileges} ON {scope} FROM '{user_name}'@'%'");
+            changes.push(Change::remove(
+                Object::Grant,
+                format!("{privileges} ON {scope} TO {user_name}"),
+                vec![Statement::new(None, statement)],
+            ));
+        }
+    }
+
+    if let Some(prune) = prune {
+        changes.extend(prune_changes(state, prune));
+    }
+
+    changes
+}
 
-        match user.role {
-            Role::Migration | Role::Replication => {
-                // for REPLICATION, scope is global, otherwise "ERROR 1221 (HY000): Incorrect usage of DB GRANT and GLOBAL PRIVILEGES"
-                let statement = format!("GRANT {} ON *.* TO '{}'@'%'", privileges, user.name);
-                sqlx::query(&statement).execute(&self.pool).await?;
+fn prune_changes(state: &State, prune: &Prune) -> Vec<Change> {
+    let mut changes = vec![];
+
+    let mut dbs: Vec<&String> = state
+        .dbs
+        .iter()
+        .filter(|db| !prune.in_config_db(db) && !SYSTEM_DBS.contains(&db.as_str()))
+        .collect();
+    dbs.sort();
+    for db in dbs {
+        if prune.dbs {
+            changes.push(Change::remove(
+                Object::DB,
+                db.to_owned(),
+                vec![Statement::new(None, format!("DROP DATABASE `{db}`"))],
+            ));
+        } else {
+            warn!(db, "db is not in config, use --prune-dbs to drop");
+            changes.push(Change::remove(Object::DB, db.to_owned(), vec![]));
+        }
+    }
+
+    let mut users: Vec<&String> = state
+        .users
+        .iter()
+        .filter(|user| !prune.in_config_user(user) && !prune.group_members.contains(user) && !protected_user(user))
+        .collect();
+    users.sort();
+    for user in users {
+        match prune.users {
+            PruneUser::Lock if !state.locked_users.contains(user) => {
+                let statement = format!("ALTER USER '{user}'@'%' ACCOUNT LOCK");
+                changes.push(Change::remove(
+                    Object::User,
+                    format!("{user} (lock)"),
+                    vec![Statement::new(None, statement)],
+                ));
             }
-            _ => {
-                let target_dbs = if let Some(db) = &user.db { &[db.to_owned()] } else { dbs };
-                for db in target_dbs {
-                    let statement = format!("GRANT {privileges} ON `{db}`.* TO '{}'@'%'", user.name);
-                    sqlx::query(&statement).execute(&self.pool).await?;
-                }
+            PruneUser::Lock => {}
+            PruneUser::Drop => {
+                let statement = format!("DROP USER IF EXISTS '{user}'@'%'");
+                changes.push(Change::remove(Object::User, user.to_owned(), vec![Statement::new(None, statement)]));
             }
         }
-        Ok(())
+    }
+
+    changes
+}
+
+impl Grant {
+    // global grants, e.g. of migration users, are always selected
+    fn selected(&self, selection: &Selection) -> bool {
+        match self.scope.strip_prefix('`').and_then(|scope| scope.strip_suffix("`.*")) {
+            Some(db) => selection.db(db),
+            None => true,
+        }
+    }
+}
+
+// built-in users and roles of mysql and cloud sql
+fn protected_user(user: &str) -> bool {
+    user == "root" || user.starts_with("mysql.") || user.starts_with("cloudsql")
+}
+
+fn grants(user: &User, dbs: &[String]) -> Vec<Grant> {
+    let privileges = match user.role {
+        Role::App => vec!["SELECT", "INSERT", "UPDATE", "DELETE"],
+        Role::Migration => vec!["CREATE", "DROP", "INDEX", "ALTER", "EXECUTE", "SELECT", "INSERT", "UPDATE", "DELETE"],
+        Role::Viewer => vec!["SELECT"],
+        Role::Replication => vec!["REPLICATION SLAVE", "SELECT", "RELOAD", "REPLICATION CLIENT", "LOCK TABLES", "EXECUTE"],
+    };
+
+    let scopes = match user.role {
+        // for REPLICATION, scope is global, otherwise "ERROR 1221 (HY000): Incorrect usage of DB GRANT and GLOBAL PRIVILEGES"
+        Role::Migration | Role::Replication => vec!["*.*".to_owned()],
+        _ => {
+            let target_dbs = if let Some(db) = &user.db { &[db.to_owned()] } else { dbs };
+            target_dbs.iter().map(|db| format!("`{db}`.*")).collect()
+        }
+    };
+
+    scopes
+        .into_iter()
+        .flat_map(|scope| {
+            privileges.iter().map(move |privilege| Grant {
+                scope: scope.to_owned(),
+                privilege: privilege.to_string(),
+            })
+        })
+        .collect()
+}
+
+// returns privileges joined by scope, in order of first appearance
+fn group_by_scope<'a>(grants: impl Iterator<Item = &'a Grant>) -> Vec<(&'a str, String)> {
+    let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+    for grant in grants {
+        match groups.iter_mut().find(|(scope, _)| *scope == grant.scope) {
+            Some((_, privileges)) => privileges.push(&grant.privilege),
+            None => groups.push((&grant.scope, vec![&grant.privilege])),
+        }
+    }
+    groups.into_iter().map(|(scope, privileges)| (scope, privileges.join(", "))).collect()
+}
+
+pub fn alter_password_statement(user: &str, password: &str) -> Statement {
+    Statement::new(None, format!("ALTER USER '{user}'@'%' IDENTIFIED BY '{password}'")).with_secret(password)
+}
+
+// grantee is in format of 'user'@'host', only users with '%' host are managed
+fn grantee_user(grantee: &str) -> Option<&str> {
+    grantee.strip_prefix('\'')?.strip_suffix("'@'%'")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::config::db_config::DBConfig;
+    use crate::db::ChangeKind;
+    use crate::db::Password;
+    use crate::db::Prune;
+    use crate::db::PruneUser;
+    use crate::db::Selection;
+    use crate::db::mysql::State;
+    use crate::db::mysql::changes;
+    use crate::db::mysql::grantee_user;
+    use crate::db::mysql::protected_user;
+    use crate::util::json;
+
+    #[test]
+    fn parse_grantee() {
+        assert_eq!(grantee_user("'app'@'%'"), Some("app"));
+        assert_eq!(grantee_user("'mysql.sys'@'localhost'"), None);
+    }
+
+    #[test]
+    fn protect_builtin_users() {
+        assert!(protected_user("root"));
+        assert!(protected_user("mysql.sys"));
+        assert!(protected_user("cloudsqlsuperuser"));
+        assert!(!protected_user("app"));
+    }
+
+    #[test]
+    fn reset_password_of_existing_user_only_if_login_failed() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "MySQL", "rootSecret": "db-root",
+            "dbs": ["orders"],
+            "users": [{"name": "app", "auth": "PASSWORD", "secret": "db-app", "role": "APP"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State::default();
+        state.users.insert("app".to_owned());
+        let passwords = |login_failed| {
+            HashMap::from([(
+                "app".to_owned(),
+                Password {
+                    value: "secret".to_owned(),
+                    generated: false,
+                    login_failed,
+                },
+            )])
+        };
+
+        let changes_of_user = |login_failed| -> Vec<String> {
+            changes(&config, &state, &passwords(login_failed), None, &Selection::default())
+                .iter()
+                .filter(|change| change.target.starts_with("app (password)"))
+                .inspect(|change| assert!(matches!(change.kind, ChangeKind::Alter)))
+                .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+                .collect()
+        };
+        assert!(changes_of_user(false).is_empty());
+        assert_eq!(changes_of_user(true), vec!["ALTER USER 'app'@'%' IDENTIFIED BY '******'"]);
+    }
+
+    #[test]
+    fn prune_keeps_iam_group_members() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "MySQL", "rootSecret": "db-root",
+            "dbs": ["orders"], "users": [{"name": "dev@example.com", "auth": "IAM", "iamType": "GROUP", "role": "VIEWER"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State::default();
+        state.dbs.insert("orders".to_owned());
+        for user in ["dev@example.com", "alice", "bob"] {
+            state.users.insert(user.to_owned());
+        }
+        let prune = Prune {
+            users: PruneUser::Drop,
+            dbs: false,
+            configs: &[&config],
+            group_members: &["alice".to_owned()],
+        };
+
+        let targets: Vec<String> = changes(&config, &state, &HashMap::new(), Some(&prune), &Selection::default())
+            .iter()
+            .filter(|change| matches!(change.kind, ChangeKind::Remove))
+            .map(|change| change.to_string())
+            .collect();
+
+        assert_eq!(targets, vec!["- user bob".to_owned()]);
     }
 }
@@ -1,17 +1,99 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
 use sqlx::Pool;
 use sqlx::Postgres;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::postgres::PgPoolOptions;
 use tracing::info;
+use tracing::warn;
 
+use crate::config::db_config::DBConfig;
+use crate::config::db_config::DBType;
 use crate::config::db_config::Role;
 use crate::config::db_config::User;
+use crate::db::Change;
+use crate::db::Object;
+use crate::db::Password;
+use crate::db::Prune;
+use crate::db::PruneUser;
+use crate::db::Selection;
+use crate::db::Statement;
+use crate::error::Result;
+
+const SETTINGS: [(&str, &str); 7] = [
+    ("auto_explain.log_min_duration", "3000"),
+    ("auto_explain.log_analyze", "true"),
+    ("auto_explain.log_buffers", "true"),
+    ("auto_explain.log_nested_statements", "true"),
+    ("auto_explain.log_settings", "true"),
+    ("auto_explain.log_verbose", "true"),
+    ("auto_explain.log_wal", "true"),
+];
+
+const SYSTEM_DBS: [&str; 2] = ["postgres", "cloudsqladmin"];
+
+// relkinds covered by "ON ALL TABLES" and "ON ALL SEQUENCES",
+// functions are only granted by default privileges, as extensions like pg_stat_statements revoke EXECUTE on their functions
+const OBJECT_KINDS: [(&str, &str); 2] = [("TABLES", "('r', 'p', 'v', 'm', 'f')"), ("SEQUENCES", "('S')")];
 
 pub struct PostgreSQL {
     options: Box<PgConnectOptions>,
 }
 
+#[derive(Default)]
+pub struct State {
+    dbs: HashMap<String, DBState>,
+    users: HashSet<String>,
+    locked_users: HashSet<String>,
+    grants: HashMap<String, HashSet<Grant>>,
+    // pg16+ membership has inherit and set options, e.g. role created by postgres is granted back to postgres with admin option only
+    membership_options: bool,
+    // (member, role) of memberships without inherit or set, which don't give access to objects of role
+    limited_memberships: HashSet<(String, String)>,
+}
+
+// only managed dbs are inspected, others are left as default
+#[derive(Default)]
+struct DBState {
+    extensions: HashSet<String>,
+    settings: HashSet<String>,
+    // number of tables or sequences in public schema
+    objects: HashMap<String, i64>,
+    // number of objects in public schema granted to role, keyed by (role, object, privilege)
+    object_privileges: HashMap<(String, String, String), i64>,
+}
+
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Grant {
+    Role(String),
+    Database {
+        db: String,
+        privilege: String,
+    },
+    Schema {
+        db: String,
+        privilege: String,
+    },
+    Objects {
+        db: String,
+        object: String,
+        privilege: String,
+    },
+    DefaultPrivilege {
+        db: String,
+        owner: String,
+        object: String,
+        privilege: String,
+    },
+}
+
+// per db group roles, APP and VIEWER users get access to db via membership
+enum GroupRole {
+    ReadOnly,
+    ReadWrite,
+}
+
 impl PostgreSQL {
     pub async fn new(public_ip: &str, user: &str, password: &str) -> Result<Self> {
         info!(user, "create postgres options");
@@ -19,126 +101,850 @@ impl PostgreSQL {
         Ok(PostgreSQL { options: Box::new(options) })
     }
 
-    pub async fn create_db(&mut self, db: &str) -> Result<()> {
+    pub async fn state(&mut self, config: &DBConfig) -> Result<State> {
+        info!("read postgres state");
+        let mut state = State::default();
         let pool = self.pool("postgres").await?;
 
-        info!(db, "check if db exists");
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(1) FROM pg_database WHERE datname = $1")
-            .bind(db)
+        let dbs: Vec<String> = sqlx::query_scalar("SELECT datname FROM pg_database WHERE NOT datistemplate")
+            .fetch_all(&pool)
+            .await?;
+        for db in dbs {
+            state.dbs.insert(db, DBState::default());
+        }
+
+        // roles without login are treated as locked users
+        let users: Vec<(String, bool)> = sqlx::query_as("SELECT rolname, rolcanlogin FROM pg_roles WHERE rolname !~ '^pg_'")
+            .fetch_all(&pool)
+            .await?;
+        for (user, can_login) in users {
+            if !can_login {
+                state.locked_users.insert(user.to_owned());
+            }
+            state.users.insert(user);
+        }
+
+        let version: i32 = sqlx::query_scalar("SELECT current_setting('server_version_num')::int")
             .fetch_one(&pool)
             .await?;
+        state.membership_options = version >= 160000;
+        let usable = if state.membership_options {
+            "a.inherit_option AND a.set_option"
+        } else {
+            "true"
+        };
+        // same membership may be granted by multiple grantors
+        let memberships: Vec<(String, String, bool)> = sqlx::query_as(&format!(
+            "SELECT m.rolname, r.rolname, bool_or({usable}) FROM pg_auth_members a JOIN pg_roles r ON a.roleid = r.oid JOIN pg_roles m ON a.member = m.oid GROUP BY m.rolname, r.rolname"
+        ))
+        .fetch_all(&pool)
+        .await?;
+        for (user, role, usable) in memberships {
+            if !usable {
+                state.limited_memberships.insert((user.to_owned(), role.to_owned()));
+            }
+            state.add_grant(user, Grant::Role(role));
+        }
 
-        if count == 0 {
-            info!(db, "create db");
-            let statement = format!(r#"CREATE DATABASE "{db}""#);
-            execute(&pool, statement).await?;
+        let db_privileges: Vec<(String, String, String)> = sqlx::query_as(
+            "SELECT r.rolname, d.datname, a.privilege_type FROM pg_database d CROSS JOIN LATERAL aclexplode(d.datacl) a JOIN pg_roles r ON a.grantee = r.oid",
+        )
+        .fetch_all(&pool)
+        .await?;
+        for (user, db, privilege) in db_privileges {
+            state.add_grant(user, Grant::Database { db, privilege });
         }
 
-        info!(db, "update db");
-        let pool = self.pool(db).await?;
+        let settings: Vec<(String, String)> = sqlx::query_as(
+            "SELECT d.datname, unnest(s.setconfig) FROM pg_db_role_setting s JOIN pg_database d ON s.setdatabase = d.oid WHERE s.setrole = 0",
+        )
+        .fetch_all(&pool)
+        .await?;
+        for (db, setting) in settings {
+            if let Some(db_state) = state.dbs.get_mut(&db) {
+                db_state.settings.insert(setting);
+            }
+        }
 
-        let statements = [
-            "CREATE EXTENSION IF NOT EXISTS pg_stat_statements".to_owned(),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_min_duration = 3000"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_analyze = true"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_buffers = true"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_nested_statements = true"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_settings = true"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_verbose = true"#),
-            format!(r#"ALTER DATABASE "{db}" SET auto_explain.log_wal = true"#),
-        ];
-        execute_all(&pool, &statements).await?;
+        for db in &config.dbs {
+            if !state.dbs.contains_key(db) {
+                continue;
+            }
+            let pool = self.pool(db).await?;
+
+            let extensions: Vec<String> = sqlx::query_scalar("SELECT extname FROM pg_extension").fetch_all(&pool).await?;
+            if let Some(db_state) = state.dbs.get_mut(db) {
+                db_state.extensions.extend(extensions);
+            }
+
+            let schema_privileges: Vec<(String, String)> = sqlx::query_as(
+                "SELECT r.rolname, a.privilege_type FROM pg_namespace n CROSS JOIN LATERAL aclexplode(n.nspacl) a JOIN pg_roles r ON a.grantee = r.oid WHERE n.nspname = 'public'",
+            )
+            .fetch_all(&pool)
+            .await?;
+            for (user, privilege) in schema_privileges {
+                state.add_grant(
+                    user,
+                    Grant::Schema {
+                        db: db.to_owned(),
+                        privilege,
+                    },
+                );
+            }
+
+            for (object, kinds) in OBJECT_KINDS {
+                let count: i64 = sqlx::query_scalar(&format!(
+                    "SELECT COUNT(1) FROM pg_class c JOIN pg_namespace n ON c.relnamespace = n.oid WHERE n.nspname = 'public' AND c.relkind IN {kinds}"
+                ))
+                .fetch_one(&pool)
+                .await?;
+                // owner privileges are implicit, e.g. tables owned by migration user
+                let privileges: Vec<(String, String, i64)> = sqlx::query_as(&format!(
+                    "SELECT r.rolname, a.privilege_type, COUNT(DISTINCT c.oid) FROM pg_class c JOIN pg_namespace n ON c.relnamespace = n.oid CROSS JOIN LATERAL aclexplode(c.relacl) a JOIN pg_roles r ON a.grantee = r.oid WHERE n.nspname = 'public' AND c.relkind IN {kinds} AND a.grantee <> c.relowner GROUP BY r.rolname, a.privilege_type"
+                ))
+                .fetch_all(&pool)
+                .await?;
+                for (user, privilege, _) in &privileges {
+                    state.add_grant(
+                        user.to_owned(),
+                        Grant::Objects {
+                            db: db.to_owned(),
+                            object: object.to_owned(),
+                            privilege: privilege.to_owned(),
+                        },
+                    );
+                }
+                if let Some(db_state) = state.dbs.get_mut(db) {
+                    db_state.objects.insert(object.to_owned(), count);
+                    for (user, privilege, count) in privileges {
+                        db_state.object_privileges.insert((user, object.to_owned(), privilege), count);
+                    }
+                }
+            }
 
+            let default_privileges: Vec<(String, String, String, String)> = sqlx::query_as(
+                "SELECT g.rolname, o.rolname, d.defaclobjtype::text, a.privilege_type FROM pg_default_acl d JOIN pg_namespace n ON d.defaclnamespace = n.oid JOIN pg_roles o ON d.defaclrole = o.oid CROSS JOIN LATERAL aclexplode(d.defaclacl) a JOIN pg_roles g ON a.grantee = g.oid WHERE n.nspname = 'public' AND a.grantee <> d.defaclrole",
+            )
+            .fetch_all(&pool)
+            .await?;
+            for (user, owner, object_type, privilege) in default_privileges {
+                if let Some(object) = default_privilege_object(&object_type) {
+                    state.add_grant(
+                        user,
+                        Grant::DefaultPrivilege {
+                            db: db.to_owned(),
+                            owner,
+                            object: object.to_owned(),
+                            privilege,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    pub async fn ping(&self, db: &str) -> Result<()> {
+        let pool = self.pool(db).await?;
+        sqlx::query("SELECT 1").execute(&pool).await?;
         Ok(())
     }
 
-    pub async fn create_user(&mut self, user: &User, password: &str) -> Result<()> {
-        let user_name = &user.name;
-        info!(user = user_name, "check if user exists");
+    pub async fn execute(&mut self, statements: &[Statement]) -> Result<()> {
+        let mut pools: HashMap<String, Pool<Postgres>> = HashMap::new();
+        for statement in statements {
+            let db = statement.db.as_deref().unwrap_or("postgres");
+            if !pools.contains_key(db) {
+                pools.insert(db.to_owned(), self.pool(db).await?);
+            }
+            info!(statement = statement.to_string(), "execute SQL");
+            sqlx::query(&statement.sql).execute(&pools[db]).await?;
+        }
+        Ok(())
+    }
 
-        let pool = self.pool("postgres").await?;
+    async fn pool(&self, db: &str) -> Result<Pool<Postgres>> {
+        let options = self.options.clone().database(db);
+        Ok(PgPoolOptions::new().max_connections(1).connect_with(options).await?)
+    }
+}
 
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(1) FROM pg_user WHERE usename = $1")
-            .bind(user_name)
-            .fetch_one(&pool)
-            .await?;
+impl State {
+    pub fn has_user(&self, user: &str) -> bool {
+        self.users.contains(user)
+    }
 
-        if count == 0 {
-            info!(user = user_name, "create user");
+    fn add_grant(&mut self, user: String, grant: Grant) {
+        self.grants.entry(user).or_default().insert(grant);
+    }
 
-            if let Role::Replication = user.role {
-                let statement = format!(r#"CREATE USER "{user_name}" WITH REPLICATION LOGIN PASSWORD '{password}'"#);
-                execute(&pool, statement).await?;
-            } else {
-                let statement = format!(r#"CREATE USER "{user_name}" WITH PASSWORD '{password}'"#);
-                execute(&pool, statement).await?;
+    fn granted(&self, user: &str, grant: &Grant) -> bool {
+        if let Grant::Role(role) = grant
+            && self.limited_memberships.contains(&(user.to_owned(), role.to_owned()))
+        {
+            return false;
+        }
+        if let Grant::Objects { db, object, privilege } = grant {
+            // "ON ALL TABLES" only covers existing tables, so it is granted only if every table is covered
+            return self.dbs.get(db).is_none_or(|db_state| {
+                let granted = db_state
+                    .object_privileges
+                    .get(&(user.to_owned(), object.to_owned(), privilege.to_owned()));
+                granted.copied().unwrap_or(0) == db_state.objects.get(object).copied().unwrap_or(0)
+            });
+        }
+        self.grants.get(user).is_some_and(|grants| grants.contains(grant))
+    }
+}
+
+pub fn changes(config: &DBConfig, state: &State, passwords: &HashMap<String, Password>, prune: Option<&Prune>, selection: &Selection) -> Vec<Change> {
+    let mut changes = vec![];
+    let dbs: Vec<&String> = config.dbs.iter().filter(|db| selection.db(db)).collect();
+    let users: Vec<&User> = config.users.iter().filter(|user| selection.user(user)).collect();
+
+    for &db in &dbs {
+        let db_state = state.dbs.get(db);
+        let mut statements = vec![];
+
+        if db_state.is_none() {
+            statements.push(Statement::new(None, format!(r#"CREATE DATABASE "{db}""#)));
+        }
+        if !db_state.is_some_and(|db_state| db_state.extensions.contains("pg_stat_statements")) {
+            statements.push(Statement::new(Some(db), "CREATE EXTENSION IF NOT EXISTS pg_stat_statements".to_owned()));
+        }
+        for (name, value) in SETTINGS {
+            if !db_state.is_some_and(|db_state| db_state.settings.contains(&format!("{name}={value}"))) {
+                statements.push(Statement::new(Some(db), format!(r#"ALTER DATABASE "{db}" SET {name} = {value}"#)));
             }
         }
 
-        Ok(())
+        match db_state {
+            _ if statements.is_empty() => {}
+            None => changes.push(Change::add(Object::DB, db.to_owned(), statements)),
+            Some(_) => changes.push(Change::alter(Object::DB, db.to_owned(), statements)),
+        }
+    }
+
+    for &db in &dbs {
+        for group_role in [GroupRole::ReadOnly, GroupRole::ReadWrite] {
+            let role = group_role.name(db);
+            if !state.users.contains(&role) {
+                changes.push(Change::add(
+                    Object::Role,
+                    role.to_owned(),
+                    vec![Statement::new(None, format!(r#"CREATE ROLE "{role}" NOLOGIN"#))],
+                ));
+            }
+        }
+    }
+
+    for user in &users {
+        let Some(password) = passwords.get(&user.name) else {
+            continue;
+        };
+        let (user_name, value) = (&user.name, &password.value);
+        let change = if state.users.contains(user_name) {
+            if !password.reset() {
+                continue;
+            }
+            Change::alter(
+                Object::User,
+                format!("{user_name} (password)"),
+                vec![alter_password_statement(user_name, value)],
+            )
+        } else {
+            let statement = match user.role {
+                Role::Replication => format!(r#"CREATE USER "{user_name}" WITH REPLICATION LOGIN PASSWORD '{value}'"#),
+                _ => format!(r#"CREATE USER "{user_name}" WITH PASSWORD '{value}'"#),
+            };
+            Change::add(
+                Object::User,
+                user_name.to_owned(),
+                vec![Statement::new(None, statement).with_secret(value)],
+            )
+        };
+        changes.push(change);
     }
 
-    pub async fn grant_user_privileges(&mut self, user: &User, dbs: &[String]) -> Result<()> {
-        info!(user = user.name, "grant user privileges");
+    for user in &users {
+        let user_name = user.db_user(&config.db_type);
+        if state.locked_users.contains(user_name) {
+            let statement = format!(r#"ALTER ROLE "{user_name}" LOGIN"#);
+            changes.push(Change::alter(
+                Object::User,
+                format!("{user_name} (unlock)"),
+                vec![Statement::new(None, statement)],
+            ));
+        }
+    }
 
-        let target_dbs = if let Some(db) = &user.db { &[db.to_owned()] } else { dbs };
-        let user_name = &user.name;
-        for db in target_dbs {
-            let pool = self.pool(db).await?;
+    // default privileges of group roles are set for migration users of all selected dbs, even if user is not selected,
+    // migration users not selected are only included if they exist, as they are not created in this run
+    let migration_users: Vec<&User> = config
+        .users
+        .iter()
+        .filter(|user| matches!(user.role, Role::Migration) && (selection.user(user) || state.users.contains(user.db_user(&config.db_type))))
+        .collect();
 
-            match user.role {
-                Role::Migration => {
-                    // migration user will be owners of all tables, thus has read/write access
-                    let statements = [
-                        format!(r#"GRANT CREATE, CONNECT ON DATABASE "{db}" TO "{user_name}""#),
-                        format!(r#"GRANT CREATE, USAGE ON SCHEMA public TO "{user_name}""#),
-                    ];
-                    execute_all(&pool, &statements).await?;
-                }
-                Role::App => {
-                    let statements = [
-                        format!(r#"GRANT CONNECT ON DATABASE "{db}" TO "{user_name}""#),
-                        format!(r#"GRANT pg_read_all_data TO "{user_name}""#),
-                        format!(r#"GRANT pg_write_all_data TO "{user_name}""#),
-                    ];
-                    execute_all(&pool, &statements).await?;
+    // root must be member of migration users to grant on their tables and alter their default privileges
+    for user in &migration_users {
+        let grant = Grant::Role(user.db_user(&config.db_type).to_owned());
+        if !state.granted("postgres", &grant) {
+            changes.push(Change::add(
+                Object::Grant,
+                grant.target("postgres"),
+                vec![grant.grant_statement("postgres", state)],
+            ));
+        }
+    }
+
+    let mut roles: Vec<(String, Vec<Grant>)> = vec![];
+    // default privileges of group roles are derived from all migration users, so they are not revoked when users are selected
+    for &db in &dbs {
+        for group_role in [GroupRole::ReadOnly, GroupRole::ReadWrite] {
+            roles.push((group_role.name(db), group_role.grants(db, &config.db_type, &migration_users)));
+        }
+    }
+    for user in &users {
+        let desired = grants(user, &config.dbs)
+            .into_iter()
+            .filter(|grant| grant.selected(config, selection))
+            .collect();
+        roles.push((user.db_user(&config.db_type).to_owned(), desired));
+    }
+
+    for (user_name, desired) in &roles {
+        for grant in desired {
+            if !state.granted(user_name, grant) {
+                changes.push(Change::add(
+                    Object::Grant,
+                    grant.target(user_name),
+                    vec![grant.grant_statement(user_name, state)],
+                ));
+            }
+        }
+
+        let current = state.grants.get(user_name);
+        let mut extra: Vec<&Grant> = current
+            .iter()
+            .flat_map(|current| current.iter())
+            .filter(|grant| !desired.contains(grant) && grant.managed() && grant.selected(config, selection))
+            .collect();
+        extra.sort();
+        for grant in extra {
+            changes.push(Change::remove(
+                Object::Grant,
+                grant.target(user_name),
+                vec![grant.revoke_statement(user_name)],
+            ));
+        }
+    }
+
+    if let Some(prune) = prune {
+        changes.extend(prune_changes(state, prune));
+    }
+
+    changes
+}
+
+fn prune_changes(state: &State, prune: &Prune) -> Vec<Change> {
+    let mut changes = vec![];
+
+    let mut dbs: Vec<&String> = state
+        .dbs
+        .keys()
+        .filter(|db| !prune.in_config_db(db) && !SYSTEM_DBS.contains(&db.as_str()))
+        .collect();
+    dbs.sort();
+    // objects and privileges of dropped user in any remaining db block DROP ROLE, cloudsqladmin doesn't allow connection
+    let mut remaining_dbs: Vec<&String> = state
+        .dbs
+        .keys()
+        .filter(|db| db.as_str() != "cloudsqladmin" && !(prune.dbs && dbs.contains(db)))
+        .collect();
+    remaining_dbs.sort();
+    for db in dbs {
+        if prune.dbs {
+            changes.push(Change::remove(
+                Object::DB,
+                db.to_owned(),
+                vec![Statement::new(None, format!(r#"DROP DATABASE "{db}""#))],
+            ));
+        } else {
+            warn!(db, "db is not in config, use --prune-dbs to drop");
+            changes.push(Change::remove(Object::DB, db.to_owned(), vec![]));
+        }
+    }
+
+    let group_roles: Vec<String> = prune
+        .config_dbs()
+        .flat_map(|db| [GroupRole::ReadOnly.name(db), GroupRole::ReadWrite.name(db)])
+        .collect();
+    let mut users: Vec<&String> = state
+        .users
+        .iter()
+        .filter(|user| !prune.in_config_user(user) && !group_roles.contains(user) && !group_member(state, prune, user) && !protected_user(user))
+        .collect();
+    users.sort();
+    for user in users {
+        match prune.users {
+            PruneUser::Lock if !state.locked_users.contains(user) => {
+                let statement = format!(r#"ALTER ROLE "{user}" NOLOGIN"#);
+                changes.push(Change::remove(
+                    Object::User,
+                    format!("{user} (lock)"),
+                    vec![Statement::new(None, statement)],
+                ));
+            }
+            PruneUser::Lock => {}
+            PruneUser::Drop => {
+                // objects owned by user, e.g. tables created by migration user, are handed over to postgres before drop
+                let mut statements = vec![Statement::new(None, format!(r#"GRANT "{user}" TO postgres"#))];
+                for &db in &remaining_dbs {
+                    statements.push(Statement::new(Some(db), format!(r#"REASSIGN OWNED BY "{user}" TO postgres"#)));
+                    statements.push(Statement::new(Some(db), format!(r#"DROP OWNED BY "{user}""#)));
                 }
-                Role::Viewer => {
-                    let statements = [
-                        format!(r#"GRANT CONNECT ON DATABASE "{db}" TO "{user_name}""#),
-                        format!(r#"GRANT pg_read_all_data TO "{user_name}""#),
-                    ];
-                    execute_all(&pool, &statements).await?;
+                statements.push(Statement::new(None, format!(r#"DROP ROLE "{user}""#)));
+                changes.push(Change::remove(Object::User, user.to_owned(), statements));
+            }
+        }
+    }
+
+    changes
+}
+
+// cloud sql creates user for each member of iam group on first login, which is member of group role
+fn group_member(state: &State, prune: &Prune, user: &str) -> bool {
+    state.grants.get(user).is_some_and(|grants| {
+        grants
+            .iter()
+            .any(|grant| matches!(grant, Grant::Role(role) if prune.in_config_group(role)))
+    })
+}
+
+pub fn alter_password_statement(user: &str, password: &str) -> Statement {
+    Statement::new(None, format!(r#"ALTER USER "{user}" WITH PASSWORD '{password}'"#)).with_secret(password)
+}
+
+// built-in roles of postgres and cloud sql
+fn protected_user(user: &str) -> bool {
+    user == "postgres" || user.starts_with("pg_") || user.starts_with("cloudsql")
+}
+
+impl Grant {
+    fn target(&self, user_name: &str) -> String {
+        match self {
+            Grant::Role(role) => format!("{role} TO {user_name}"),
+            Grant::Database { db, privilege } => format!("{privilege} ON DATABASE {db} TO {user_name}"),
+            Grant::Schema { db, privilege } => format!("{privilege} ON SCHEMA {db}.public TO {user_name}"),
+            Grant::Objects { db, object, privilege } => format!("{privilege} ON ALL {object} IN SCHEMA {db}.public TO {user_name}"),
+            Grant::DefaultPrivilege {
+                db,
+                owner,
+                object,
+                privilege,
+            } => format!("{privilege} ON {object} IN SCHEMA {db}.public TO {user_name}, default for role {owner}"),
+        }
+    }
+
+    fn grant_statement(&self, user_name: &str, state: &State) -> Statement {
+        match self {
+            // options of existing membership are updated, e.g. one without inherit or set
+            Grant::Role(role) if state.membership_options => {
+                Statement::new(None, format!(r#"GRANT "{role}" TO "{user_name}" WITH INHERIT TRUE, SET TRUE"#))
+            }
+            Grant::Role(role) => Statement::new(None, format!(r#"GRANT "{role}" TO "{user_name}""#)),
+            Grant::Database { db, privilege } => Statement::new(None, format!(r#"GRANT {privilege} ON DATABASE "{db}" TO "{user_name}""#)),
+            Grant::Schema { db, privilege } => Statement::new(Some(db), format!(r#"GRANT {privilege} ON SCHEMA public TO "{user_name}""#)),
+            Grant::Objects { db, object, privilege } => Statement::new(
+                Some(db),
+                format!(r#"GRANT {privilege} ON ALL {object} IN SCHEMA public TO "{user_name}""#),
+            ),
+            Grant::DefaultPrivilege {
+                db,
+                owner,
+                object,
+                privilege,
+            } => Statement::new(
+                Some(db),
+                format!(r#"ALTER DEFAULT PRIVILEGES FOR ROLE "{owner}" IN SCHEMA public GRANT {privilege} ON {object} TO "{user_name}""#),
+            ),
+        }
+    }
+
+    fn revoke_statement(&self, user_name: &str) -> Statement {
+        match self {
+            Grant::Role(role) => Statement::new(None, format!(r#"REVOKE "{role}" FROM "{user_name}""#)),
+            Grant::Database { db, privilege } => Statement::new(None, format!(r#"REVOKE {privilege} ON DATABASE "{db}" FROM "{user_name}""#)),
+            Grant::Schema { db, privilege } => Statement::new(Some(db), format!(r#"REVOKE {privilege} ON SCHEMA public FROM "{user_name}""#)),
+            Grant::Objects { db, object, privilege } => Statement::new(
+                Some(db),
+                format!(r#"REVOKE {privilege} ON ALL {object} IN SCHEMA public FROM "{user_name}""#),
+            ),
+            Grant::DefaultPrivilege {
+                db,
+                owner,
+                object,
+                privilege,
+            } => Statement::new(
+                Some(db),
+                format!(r#"ALTER DEFAULT PRIVILEGES FOR ROLE "{owner}" IN SCHEMA public REVOKE {privilege} ON {object} FROM "{user_name}""#),
+            ),
+        }
+    }
+
+    // memberships granted by cloud sql, e.g. cloudsqliamuser for IAM users, must be kept
+    fn managed(&self) -> bool {
+        !matches!(self, Grant::Role(role) if role.starts_with("cloudsql"))
+    }
+
+    // memberships of group roles belong to their db, other memberships are always selected
+    fn selected(&self, config: &DBConfig, selection: &Selection) -> bool {
+        match self {
+            Grant::Role(role) => config
+                .dbs
+                .iter()
+                .filter(|db| [GroupRole::ReadOnly.name(db), GroupRole::ReadWrite.name(db)].contains(role))
+                .all(|db| selection.db(db)),
+            Grant::Database { db, .. } | Grant::Schema { db, .. } | Grant::Objects { db, .. } | Grant::DefaultPrivilege { db, .. } => {
+                selection.db(db)
+            }
+        }
+    }
+}
+
+fn grants(user: &User, dbs: &[String]) -> Vec<Grant> {
+    let target_dbs = if let Some(db) = &user.db { &[db.to_owned()] } else { dbs };
+    let mut grants = vec![];
+    for db in target_dbs {
+        let database = |privilege: &str| Grant::Database {
+            db: db.to_owned(),
+            privilege: privilege.to_owned(),
+        };
+        let schema = |privilege: &str| Grant::Schema {
+            db: db.to_owned(),
+            privilege: privilege.to_owned(),
+        };
+        match user.role {
+            // migration user will be owners of all tables, thus has read/write access
+            Role::Migration => grants.extend([database("CREATE"), database("CONNECT"), schema("CREATE"), schema("USAGE")]),
+            Role::App => grants.push(Grant::Role(GroupRole::ReadWrite.name(db))),
+            Role::Viewer => grants.push(Grant::Role(GroupRole::ReadOnly.name(db))),
+            Role::Replication => grants.extend([database("CONNECT"), schema("USAGE")]),
+        }
+    }
+
+    // replication reads all dbs, predefined role is cluster wide
+    if let Role::Replication = user.role {
+        grants.push(Grant::Role("pg_read_all_data".to_owned()));
+    }
+    grants
+}
+
+impl GroupRole {
+    fn name(&self, db: &str) -> String {
+        match self {
+            GroupRole::ReadOnly => format!("{db}_readonly"),
+            GroupRole::ReadWrite => format!("{db}_readwrite"),
+        }
+    }
+
+    fn privileges(&self) -> [(&'static str, &'static [&'static str]); 3] {
+        match self {
+            GroupRole::ReadOnly => [("TABLES", &["SELECT"]), ("SEQUENCES", &["SELECT"]), ("FUNCTIONS", &["EXECUTE"])],
+            GroupRole::ReadWrite => [
+                ("TABLES", &["SELECT", "INSERT", "UPDATE", "DELETE"]),
+                ("SEQUENCES", &["USAGE", "SELECT"]),
+                ("FUNCTIONS", &["EXECUTE"]),
+            ],
+        }
+    }
+
+    fn grants(&self, db: &str, db_type: &DBType, migration_users: &[&User]) -> Vec<Grant> {
+        let mut grants = vec![
+            Grant::Database {
+                db: db.to_owned(),
+                privilege: "CONNECT".to_owned(),
+            },
+            Grant::Schema {
+                db: db.to_owned(),
+                privilege: "USAGE".to_owned(),
+            },
+        ];
+        for (object, privileges) in self.privileges() {
+            if OBJECT_KINDS.iter().any(|(kind, _)| *kind == object) {
+                for privilege in privileges {
+                    grants.push(Grant::Objects {
+                        db: db.to_owned(),
+                        object: object.to_owned(),
+                        privilege: privilege.to_string(),
+                    });
                 }
-                Role::Replication => {
-                    let statements = [
-                        format!(r#"GRANT CONNECT ON DATABASE "{db}" TO "{user_name}""#),
-                        format!(r#"GRANT USAGE ON SCHEMA public TO "{user_name}""#),
-                        format!(r#"GRANT pg_read_all_data TO "{user_name}""#),
-                    ];
-                    execute_all(&pool, &statements).await?;
+            }
+        }
+        // each migration user owns the objects it creates, so default privileges are set per migration user
+        for owner in owners(db_type, migration_users, db) {
+            for (object, privileges) in self.privileges() {
+                for privilege in privileges {
+                    grants.push(Grant::DefaultPrivilege {
+                        db: db.to_owned(),
+                        owner: owner.to_owned(),
+                        object: object.to_owned(),
+                        privilege: privilege.to_string(),
+                    });
                 }
-            };
+            }
         }
-        Ok(())
+        grants
     }
+}
 
-    async fn pool(&self, db: &str) -> Result<Pool<Postgres>> {
-        let options = self.options.clone().database(db);
-        Ok(PgPoolOptions::new().max_connections(1).connect_with(options).await?)
-    }
+// tables are created and owned by migration users
+fn owners<'a>(db_type: &'a DBType, migration_users: &'a [&User], db: &str) -> impl Iterator<Item = &'a str> {
+    migration_users
+        .iter()
+        .filter(move |user| user.db.as_ref().is_none_or(|user_db| user_db == db))
+        .map(|user| user.db_user(db_type))
 }
 
-async fn execute(pool: &Pool<Postgres>, statement: String) -> Result<()> {
-    info!(statement, "execute SQL");
-    sqlx::query(&statement).execute(pool).await?;
-    Ok(())
+fn default_privilege_object(object_type: &str) -> Option<&'static str> {
+    match object_type {
+        "r" => Some("TABLES"),
+        "S" => Some("SEQUENCES"),
+        "f" => Some("FUNCTIONS"),
+        _ => None,
+    }
 }
 
-async fn execute_all(pool: &Pool<Postgres>, statements: &[String]) -> Result<()> {
-    for statement in statements {
-        info!(statement, "execute SQL");
-        sqlx::query(statement).execute(pool).await?;
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::config::db_config::DBConfig;
+    use crate::db::ChangeKind;
+    use crate::db::Prune;
+    use crate::db::PruneUser;
+    use crate::db::Selection;
+    use crate::db::postgresql::DBState;
+    use crate::db::postgresql::Grant;
+    use crate::db::postgresql::State;
+    use crate::db::postgresql::changes;
+    use crate::util::json;
+
+    #[test]
+    fn changes_on_empty_instance() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+            "dbs": ["orders"],
+            "users": [{"name": "migration", "auth": "IAM", "role": "MIGRATION"}, {"name": "batch-migration", "auth": "IAM", "db": "orders", "role": "MIGRATION"},
+                {"name": "viewer", "auth": "IAM", "role": "VIEWER"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+
+        let statements: Vec<String> = changes(&config, &State::default(), &HashMap::new(), None, &Selection::default())
+            .iter()
+            .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+            .collect();
+
+        assert!(statements.contains(&r#"CREATE ROLE "orders_readonly" NOLOGIN"#.to_owned()));
+        assert!(statements.contains(&r#"GRANT "migration" TO "postgres""#.to_owned()));
+        assert!(statements.contains(
+            &r#"[orders] ALTER DEFAULT PRIVILEGES FOR ROLE "migration" IN SCHEMA public GRANT SELECT ON TABLES TO "orders_readonly""#.to_owned()
+        ));
+        assert!(
+            statements.contains(
+                &r#"[orders] ALTER DEFAULT PRIVILEGES FOR ROLE "batch-migration" IN SCHEMA public GRANT USAGE ON SEQUENCES TO "orders_readwrite""#
+                    .to_owned()
+            )
+        );
+        assert!(
+            statements.contains(
+                &r#"[orders] ALTER DEFAULT PRIVILEGES FOR ROLE "batch-migration" IN SCHEMA public GRANT EXECUTE ON FUNCTIONS TO "orders_readonly""#
+                    .to_owned()
+            )
+        );
+        assert!(statements.contains(&r#"GRANT "orders_readonly" TO "viewer""#.to_owned()));
+        assert!(!statements.iter().any(|statement| statement.contains("pg_read_all_data")));
+    }
+
+    #[test]
+    fn changes_of_selected_users_and_dbs() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+            "dbs": ["orders", "payments"],
+            "users": [{"name": "migration", "auth": "IAM", "role": "MIGRATION"}, {"name": "batch-migration", "auth": "IAM", "role": "MIGRATION"},
+                {"name": "viewer", "auth": "IAM", "role": "VIEWER"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State::default();
+        state.users.insert("migration".to_owned());
+        let selection = Selection {
+            users: vec!["viewer".to_owned()],
+            dbs: vec!["orders".to_owned()],
+        };
+
+        let statements: Vec<String> = changes(&config, &state, &HashMap::new(), None, &selection)
+            .iter()
+            .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+            .collect();
+
+        assert!(statements.contains(&r#"GRANT "orders_readonly" TO "viewer""#.to_owned()));
+        assert!(statements.contains(
+            &r#"[orders] ALTER DEFAULT PRIVILEGES FOR ROLE "migration" IN SCHEMA public GRANT SELECT ON TABLES TO "orders_readonly""#.to_owned()
+        ));
+        assert!(!statements.iter().any(|statement| statement.contains("payments")));
+        assert!(statements.contains(&r#"GRANT "migration" TO "postgres""#.to_owned()));
+        // not selected and not created yet, so it can't own objects
+        assert!(!statements.iter().any(|statement| statement.contains("batch-migration")));
+    }
+
+    #[test]
+    fn prune_keeps_other_configs_of_instance() {
+        let config = |dbs: &str, users: &str| -> DBConfig {
+            json::from_json(&format!(
+                r#"{{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+                "dbs": [{dbs}], "users": [{users}], "endpoint": {{"name": "db", "ns": "app", "path": "kube/db.yaml"}}}}"#
+            ))
+            .unwrap()
+        };
+        let orders = config(r#""orders""#, r#"{"name": "orders-app", "auth": "IAM", "role": "APP"}"#);
+        let payments = config(r#""payments""#, r#"{"name": "payments-app", "auth": "IAM", "role": "APP"}"#);
+        let mut state = State::default();
+        for db in ["orders", "payments", "legacy"] {
+            state.dbs.insert(db.to_owned(), DBState::default());
+        }
+        for user in ["orders-app", "payments-app", "payments_readonly", "payments_readwrite", "legacy-app"] {
+            state.users.insert(user.to_owned());
+        }
+        let prune = Prune {
+            users: PruneUser::Drop,
+            dbs: true,
+            configs: &[&orders, &payments],
+            group_members: &[],
+        };
+
+        let targets: Vec<String> = changes(&orders, &state, &HashMap::new(), Some(&prune), &Selection::default())
+            .iter()
+            .filter(|change| matches!(change.kind, ChangeKind::Remove))
+            .map(|change| change.to_string())
+            .collect();
+
+        assert_eq!(targets, vec!["- db legacy".to_owned(), "- user legacy-app".to_owned()]);
+    }
+
+    #[test]
+    fn prune_clears_owned_objects_in_remaining_dbs() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+            "dbs": ["orders"], "users": [{"name": "orders-app", "auth": "IAM", "role": "APP"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State::default();
+        for db in ["postgres", "cloudsqladmin", "orders", "legacy"] {
+            state.dbs.insert(db.to_owned(), DBState::default());
+        }
+        for user in ["orders-app", "legacy_readonly", "legacy-app"] {
+            state.users.insert(user.to_owned());
+        }
+        let prune = Prune {
+            users: PruneUser::Drop,
+            dbs: false,
+            configs: &[&config],
+            group_members: &[],
+        };
+
+        let changes = changes(&config, &state, &HashMap::new(), Some(&prune), &Selection::default());
+        let statements = |target: &str| -> Vec<String> {
+            changes
+                .iter()
+                .filter(|change| matches!(change.kind, ChangeKind::Remove) && change.target == target)
+                .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+                .collect()
+        };
+
+        assert!(statements("legacy").is_empty());
+        assert_eq!(
+            statements("legacy_readonly"),
+            vec![
+                r#"GRANT "legacy_readonly" TO postgres"#,
+                r#"[legacy] REASSIGN OWNED BY "legacy_readonly" TO postgres"#,
+                r#"[legacy] DROP OWNED BY "legacy_readonly""#,
+                r#"[orders] REASSIGN OWNED BY "legacy_readonly" TO postgres"#,
+                r#"[orders] DROP OWNED BY "legacy_readonly""#,
+                r#"[postgres] REASSIGN OWNED BY "legacy_readonly" TO postgres"#,
+                r#"[postgres] DROP OWNED BY "legacy_readonly""#,
+                r#"DROP ROLE "legacy_readonly""#,
+            ]
+        );
+        assert!(statements("legacy-app").contains(&r#"[legacy] DROP OWNED BY "legacy-app""#.to_owned()));
+    }
+
+    #[test]
+    fn prune_keeps_members_of_config_iam_group() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+            "dbs": ["orders"], "users": [{"name": "dev@example.com", "auth": "IAM", "iamType": "GROUP", "role": "VIEWER"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State::default();
+        state.dbs.insert("orders".to_owned(), DBState::default());
+        for user in ["dev@example.com", "alice@example.com", "bob@example.com"] {
+            state.users.insert(user.to_owned());
+        }
+        state.add_grant("alice@example.com".to_owned(), Grant::Role("dev@example.com".to_owned()));
+        let prune = Prune {
+            users: PruneUser::Lock,
+            dbs: false,
+            configs: &[&config],
+            group_members: &[],
+        };
+
+        let targets: Vec<String> = changes(&config, &state, &HashMap::new(), Some(&prune), &Selection::default())
+            .iter()
+            .filter(|change| matches!(change.kind, ChangeKind::Remove))
+            .map(|change| change.to_string())
+            .collect();
+
+        assert_eq!(targets, vec!["- user bob@example.com (lock)".to_owned()]);
+    }
+
+    #[test]
+    fn grant_root_membership_without_inherit_or_set() {
+        let config: DBConfig = json::from_json(
+            r#"{"version": "0.6.3", "project": "project", "env": "dev", "instance": "db", "type": "PostgreSQL", "rootSecret": "db-root",
+            "dbs": ["orders"], "users": [{"name": "migration", "auth": "PASSWORD", "secret": "db-migration", "role": "MIGRATION"}],
+            "endpoint": {"name": "db", "ns": "app", "path": "kube/db.yaml"}}"#,
+        )
+        .unwrap();
+        let mut state = State {
+            membership_options: true,
+            ..State::default()
+        };
+        state.dbs.insert("orders".to_owned(), DBState::default());
+        state.users.insert("migration".to_owned());
+        state.add_grant("postgres".to_owned(), Grant::Role("migration".to_owned()));
+        state.limited_memberships.insert(("postgres".to_owned(), "migration".to_owned()));
+
+        let statements: Vec<String> = changes(&config, &state, &HashMap::new(), None, &Selection::default())
+            .iter()
+            .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+            .collect();
+        assert!(statements.contains(&r#"GRANT "migration" TO "postgres" WITH INHERIT TRUE, SET TRUE"#.to_owned()));
+
+        state.limited_memberships.clear();
+        let statements: Vec<String> = changes(&config, &state, &HashMap::new(), None, &Selection::default())
+            .iter()
+            .flat_map(|change| change.statements.iter().map(|statement| statement.to_string()))
+            .collect();
+        assert!(
+            !statements
+                .iter()
+                .any(|statement| statement.starts_with(r#"GRANT "migration" TO "postgres""#))
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,70 @@
+use std::io;
+use std::path::PathBuf;
+
+// anyhow error (1) and clap usage error (2) are taken
+const DRIFT_EXIT_CODE: i32 = 3;
+const CONFIG_EXIT_CODE: i32 = 4;
+const GCLOUD_EXIT_CODE: i32 = 5;
+const DATABASE_EXIT_CODE: i32 = 6;
+const IO_EXIT_CODE: i32 = 7;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid config, {0}")]
+    Config(String),
+    #[error("gcloud api failed, url={url}, status={status}, response={body}")]
+    GcloudApi { url: String, status: u16, body: String },
+    // failures other than api response, e.g. connection, auth and long-running operation
+    #[error("gcloud failed, {0}")]
+    Gcloud(String),
+    #[error("db failed, {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("io failed, path={}, {source}", path.to_string_lossy())]
+    IO { path: PathBuf, source: io::Error },
+    // live state differs from configs in check mode
+    #[error("drift detected, configs={0}")]
+    Drift(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Error {
+        move |source| Error::IO { path: path.into(), source }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => CONFIG_EXIT_CODE,
+            Error::GcloudApi { .. } | Error::Gcloud(_) => GCLOUD_EXIT_CODE,
+            Error::Database(_) => DATABASE_EXIT_CODE,
+            Error::IO { .. } => IO_EXIT_CODE,
+            Error::Drift(_) => DRIFT_EXIT_CODE,
+        }
+    }
+}
+
+// errors may be wrapped with context, the innermost categorized one decides exit code
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<Error>())
+        .last()
+        .map(Error::exit_code)
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use crate::error::Error;
+    use crate::error::exit_code;
+
+    #[test]
+    fn exit_code_by_category() {
+        let err: std::result::Result<(), Error> = Err(Error::Config("invalid".to_owned()));
+        assert_eq!(exit_code(&err.context("failed to sync db").unwrap_err()), 4);
+        assert_eq!(exit_code(&Error::Drift(2).into()), 3);
+        assert_eq!(exit_code(&anyhow::anyhow!("unknown")), 1);
+    }
+}
@@ -1,81 +1,242 @@
+use crate::error::Error;
+use crate::error::Result;
 use crate::util::http_client::HTTP_CLIENT;
 use crate::util::json;
+use chrono::DateTime;
+use chrono::Utc;
+use reqwest::Method;
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use reqwest::header::RETRY_AFTER;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::env;
-use std::error::Error;
+use std::error::Error as _;
 use std::fmt::Debug;
-use std::ops::Deref;
-use std::process::Command;
-use std::process::Stdio;
-use std::sync::LazyLock;
+use std::time::Duration;
 use tracing::info;
+use tracing::warn;
 
+mod auth;
 pub mod secret_manager;
 pub mod sql_admin;
 
-pub(in crate::gcloud) async fn get<Response>(url: &str) -> Option<Response>
+pub use auth::impersonate;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+// Retry-After is honored in full, longer delay fails the call instead of retrying before server allows
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(300);
+
+// GCLOUD_API_URL points all apis to same host, e.g. local stub, GCLOUD_{API}_URL overrides single api, e.g. GCLOUD_SQL_ADMIN_URL
+pub(in crate::gcloud) enum Api {
+    SQLAdmin,
+    SecretManager,
+    IAMCredentials,
+}
+
+impl Api {
+    pub(in crate::gcloud) fn url(&self, path: &str) -> String {
+        let (name, default_url) = match self {
+            Api::SQLAdmin => ("SQL_ADMIN", "https://sqladmin.googleapis.com"),
+            Api::SecretManager => ("SECRET_MANAGER", "https://secretmanager.googleapis.com"),
+            Api::IAMCredentials => ("IAM_CREDENTIALS", "https://iamcredentials.googleapis.com"),
+        };
+        let base_url = env::var(format!("GCLOUD_{name}_URL"))
+            .or_else(|_| env::var("GCLOUD_API_URL"))
+            .unwrap_or_else(|_| default_url.to_owned());
+        format!("{}/v1/{path}", base_url.trim_end_matches('/'))
+    }
+}
+
+pub(in crate::gcloud) async fn get<Response>(url: &str) -> Result<Option<Response>>
 where
     Response: DeserializeOwned,
 {
-    let response = HTTP_CLIENT
-        .get(url)
-        .bearer_auth(TOKEN.deref())
-        .header("Accept", "application/json")
-        .send()
-        .await
-        .unwrap_or_else(|err| panic!("{err}, source={:?}", err.source()));
-
-    let status = response.status();
-    let text = response.text().await.unwrap_or_else(|err| panic!("{err}"));
+    let (status, text) = call(Method::GET, url, None).await?;
     if status == 404 {
-        return None;
+        return Ok(None);
     }
     if status != 200 {
-        panic!("failed to call api, status={status}, response={text}");
+        return Err(api_error(url, status, text));
     }
-    Some(json::from_json(&text))
+    Ok(Some(parse_response(url, &text)?))
+}
+
+pub(in crate::gcloud) async fn post<Request, Response>(url: &str, request: &Request) -> Result<Response>
+where
+    Request: Serialize + Debug,
+    Response: DeserializeOwned,
+{
+    send(Method::POST, url, request).await
+}
+
+pub(in crate::gcloud) async fn patch<Request, Response>(url: &str, request: &Request) -> Result<Response>
+where
+    Request: Serialize + Debug,
+    Response: DeserializeOwned,
+{
+    send(Method::PATCH, url, request).await
 }
 
-pub(in crate::gcloud) async fn post<Request, Response>(url: &str, request: &Request) -> Response
+async fn send<Request, Response>(method: Method, url: &str, request: &Request) -> Result<Response>
 where
     Request: Serialize + Debug,
     Response: DeserializeOwned,
 {
     let body = json::to_json(request);
-    let response = HTTP_CLIENT
-        .post(url)
-        .bearer_auth(TOKEN.deref())
-        .header("Content-Type", "application/json")
-        .header("Accept", "application/json")
-        .body(body)
-        .send()
-        .await
-        .unwrap_or_else(|err| panic!("{err}, source={:?}", err.source()));
-
-    let status = response.status();
-    let text = response.text().await.unwrap_or_else(|err| panic!("{err}"));
+    let (status, text) = call(method, url, Some(body)).await?;
     if status != 200 {
-        panic!("failed to call api, status={status}, response={text}");
+        return Err(api_error(url, status, text));
     }
-    json::from_json(&text)
+    parse_response(url, &text)
 }
 
-static TOKEN: LazyLock<String> = LazyLock::new(|| {
-    let token = env::var("GCLOUD_AUTH_TOKEN");
-    if let Ok(token) = token {
-        info!("auth gcloud via GCLOUD_AUTH_TOKEN env");
-        return token;
+fn api_error(url: &str, status: StatusCode, body: String) -> Error {
+    Error::GcloudApi {
+        url: url.to_owned(),
+        status: status.as_u16(),
+        body,
     }
+}
 
-    info!("auth gcloud via gcloud auth print-access-token");
-    let output = Command::new("gcloud")
-        .args(["auth", "print-access-token"])
-        .stdout(Stdio::piped())
-        .output()
-        .unwrap_or_else(|err| panic!("please setup gcloud or set GCLOUD_AUTH_TOKEN env, err={err}"));
-
-    let token = String::from_utf8(output.stdout).expect("token should be in utf-8");
-    // print token contains '\n' at ends
-    token.trim_ascii_end().to_string()
-});
+fn parse_response<Response>(url: &str, text: &str) -> Result<Response>
+where
+    Response: DeserializeOwned,
+{
+    json::from_json(text).map_err(|err| Error::Gcloud(format!("failed to parse api response, url={url}, err={err}")))
+}
+
+// token may be revoked or expire earlier than expected, so refresh and retry once on 401
+// 429 and connection errors are retried with backoff, as request is not processed,
+// 5xx and timeouts are only retried for idempotent methods, as POST may have been applied, e.g. secret version added,
+// other statuses are returned to caller
+async fn call(method: Method, url: &str, body: Option<String>) -> Result<(StatusCode, String)> {
+    let max_attempts = max_attempts()?;
+    let idempotent = method != Method::POST;
+    let mut token = auth::token().await?;
+    let mut refreshed = false;
+    let mut attempt = 1;
+    loop {
+        let mut request = HTTP_CLIENT
+            .request(method.clone(), url)
+            .bearer_auth(&token)
+            .header("Accept", "application/json");
+        if let Some(body) = &body {
+            request = request.header("Content-Type", "application/json").body(body.to_owned());
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(err) if (err.is_connect() || (err.is_timeout() && idempotent)) && attempt < max_attempts => {
+                let delay = backoff(attempt);
+                warn!(url, attempt, ?delay, "failed to connect gcloud api, retry, err={err}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+            Err(err) => {
+                return Err(Error::Gcloud(format!(
+                    "failed to call api, url={url}, attempts={attempt}, err={err}, source={:?}",
+                    err.source()
+                )));
+            }
+        };
+
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let text = response
+            .text()
+            .await
+            .map_err(|err| Error::Gcloud(format!("failed to read api response, url={url}, err={err}")))?;
+        if status == StatusCode::UNAUTHORIZED && !refreshed {
+            info!(url, "gcloud api returned 401, refresh token and retry");
+            token = auth::refresh_token(&token).await?;
+            refreshed = true;
+            continue;
+        }
+        if (status == StatusCode::TOO_MANY_REQUESTS || (status.is_server_error() && idempotent)) && attempt < max_attempts {
+            if let Some(delay) = retry_after.filter(|delay| *delay > RETRY_AFTER_MAX) {
+                warn!(url, %status, ?delay, "gcloud api asks to retry later than max delay, give up");
+                return Ok((status, text));
+            }
+            let delay = retry_after.unwrap_or_else(|| backoff(attempt));
+            warn!(url, %status, attempt, ?delay, "gcloud api returned retryable status, retry, response={text}");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            continue;
+        }
+        return Ok((status, text));
+    }
+}
+
+fn max_attempts() -> Result<u32> {
+    match env::var("GCLOUD_MAX_ATTEMPTS") {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|attempts| *attempts > 0)
+            .ok_or_else(|| Error::Config(format!("GCLOUD_MAX_ATTEMPTS must be positive integer, value={value}"))),
+        Err(_) => Ok(DEFAULT_MAX_ATTEMPTS),
+    }
+}
+
+// exponential backoff with full jitter, refer to https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+fn backoff(attempt: u32) -> Duration {
+    let max_delay = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(attempt - 1)).min(BACKOFF_MAX);
+    Duration::from_millis(rand::random_range(0..=max_delay.as_millis() as u64))
+}
+
+// Retry-After is either delay in seconds or http date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::TimeDelta;
+    use chrono::Utc;
+    use reqwest::header::HeaderMap;
+    use reqwest::header::HeaderValue;
+    use reqwest::header::RETRY_AFTER;
+
+    use crate::gcloud::BACKOFF_MAX;
+    use crate::gcloud::RETRY_AFTER_MAX;
+    use crate::gcloud::backoff;
+    use crate::gcloud::retry_after;
+
+    #[test]
+    fn parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3600)));
+
+        let date = (Utc::now() + TimeDelta::seconds(10)).to_rfc2822();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(8) && delay <= Duration::from_secs(10), "{delay:?}");
+        let date = (Utc::now() + TimeDelta::hours(1)).to_rfc2822();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        assert!(retry_after(&headers).unwrap() > RETRY_AFTER_MAX);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn cap_backoff() {
+        assert!(backoff(1) <= Duration::from_millis(500));
+        assert!(backoff(20) <= BACKOFF_MAX);
+    }
+}
@@ -0,0 +1,160 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::OnceLock;
+
+use chrono::DateTime;
+use chrono::TimeDelta;
+use chrono::Utc;
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::util::json;
+
+mod impersonation;
+mod metadata;
+mod service_account;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+static TOKEN: Mutex<Option<Token>> = Mutex::const_new(None);
+static IMPERSONATE_SERVICE_ACCOUNT: OnceLock<String> = OnceLock::new();
+
+struct Token {
+    value: String,
+    // token from env or gcloud cli is used as is
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// refresh ahead of expiry, so token won't expire during request
+const EXPIRY_MARGIN: TimeDelta = TimeDelta::minutes(5);
+
+// oauth2 token response, returned by both metadata server and token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+impl Token {
+    fn expiring(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at - EXPIRY_MARGIN <= Utc::now())
+    }
+}
+
+impl From<TokenResponse> for Token {
+    fn from(response: TokenResponse) -> Self {
+        Token {
+            value: response.access_token,
+            expires_at: Some(Utc::now() + TimeDelta::seconds(response.expires_in)),
+        }
+    }
+}
+
+// token requests are sent without retry or gcloud::call, as gcloud::call requires token itself
+async fn fetch<Response>(source: &str, url: &str, request: RequestBuilder) -> Result<Response>
+where
+    Response: DeserializeOwned,
+{
+    let response = request
+        .send()
+        .await
+        .map_err(|err| Error::Gcloud(format!("failed to call {source}, url={url}, err={err}")))?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|err| Error::Gcloud(format!("failed to read {source} response, url={url}, err={err}")))?;
+    if status != 200 {
+        return Err(Error::GcloudApi {
+            url: url.to_owned(),
+            status: status.as_u16(),
+            body: text,
+        });
+    }
+    json::from_json(&text).map_err(|err| Error::Gcloud(format!("failed to parse {source} response, url={url}, err={err}")))
+}
+
+pub(in crate::gcloud) async fn token() -> Result<String> {
+    let mut token = TOKEN.lock().await;
+    if token.as_ref().is_none_or(Token::expiring) {
+        *token = Some(fetch_token().await?);
+    }
+    Ok(token.as_ref().unwrap().value.to_owned())
+}
+
+// called when rejected with 401, token may already be refreshed by another request
+pub(in crate::gcloud) async fn refresh_token(rejected: &str) -> Result<String> {
+    let mut token = TOKEN.lock().await;
+    if token.as_ref().is_none_or(|token| token.value == rejected) {
+        *token = Some(fetch_token().await?);
+    }
+    Ok(token.as_ref().unwrap().value.to_owned())
+}
+
+pub fn impersonate(service_account: String) {
+    IMPERSONATE_SERVICE_ACCOUNT
+        .set(service_account)
+        .expect("impersonated service account should only be set once");
+}
+
+async fn fetch_token() -> Result<Token> {
+    let token = fetch_source_token().await?;
+    match IMPERSONATE_SERVICE_ACCOUNT.get() {
+        Some(service_account) => {
+            info!(service_account, "impersonate service account");
+            // source token is fetched again on refresh, so only expiry of impersonated token matters
+            impersonation::token(&token.value, service_account).await
+        }
+        None => Ok(token),
+    }
+}
+
+// credential of caller, which is used directly or to impersonate service account
+async fn fetch_source_token() -> Result<Token> {
+    if let Ok(token) = env::var("GCLOUD_AUTH_TOKEN") {
+        info!("auth gcloud via GCLOUD_AUTH_TOKEN env");
+        return Ok(Token {
+            value: token,
+            expires_at: None,
+        });
+    }
+
+    if let Ok(path) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+        info!(path, "auth gcloud via GOOGLE_APPLICATION_CREDENTIALS env");
+        return service_account::token(Path::new(&path)).await;
+    }
+
+    let output = Command::new("gcloud")
+        .args(["auth", "print-access-token"])
+        .stdout(Stdio::piped())
+        .output();
+    match output {
+        Ok(output) => {
+            info!("auth gcloud via gcloud auth print-access-token");
+            if !output.status.success() {
+                return Err(Error::Gcloud(format!(
+                    "failed to run gcloud auth print-access-token, please login via gcloud or set GCLOUD_AUTH_TOKEN env, status={}",
+                    output.status
+                )));
+            }
+            let token = String::from_utf8(output.stdout).map_err(|err| Error::Gcloud(format!("token should be in utf-8, err={err}")))?;
+            // print token contains '\n' at ends
+            Ok(Token {
+                value: token.trim_ascii_end().to_string(),
+                expires_at: None,
+            })
+        }
+        // gcloud cli is not installed, e.g. running as GKE job with workload identity
+        Err(err) => {
+            info!("gcloud cli is not available, auth gcloud via metadata server, err={err}");
+            metadata::token().await
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::gcloud::Api;
+use crate::gcloud::auth::SCOPE;
+use crate::gcloud::auth::Token;
+use crate::gcloud::auth::fetch;
+use crate::util::http_client::HTTP_CLIENT;
+use crate::util::json;
+
+#[derive(Serialize, Debug)]
+struct GenerateAccessTokenRequest<'a> {
+    scope: [&'a str; 1],
+    lifetime: &'a str,
+}
+
+#[derive(Deserialize)]
+struct GenerateAccessTokenResponse {
+    #[serde(rename(deserialize = "accessToken"))]
+    access_token: String,
+    #[serde(rename(deserialize = "expireTime"))]
+    expire_time: DateTime<Utc>,
+}
+
+// caller must have roles/iam.serviceAccountTokenCreator on service account
+pub async fn token(source_token: &str, service_account: &str) -> Result<Token> {
+    let url = Api::IAMCredentials.url(&format!("projects/-/serviceAccounts/{service_account}:generateAccessToken"));
+    let request = GenerateAccessTokenRequest {
+        scope: [SCOPE],
+        lifetime: "3600s",
+    };
+    let request = HTTP_CLIENT
+        .post(&url)
+        .bearer_auth(source_token)
+        .header("Content-Type", "application/json")
+        .body(json::to_json(&request));
+    let response: GenerateAccessTokenResponse = fetch("iam credentials api", &url, request).await?;
+    Ok(Token {
+        value: response.access_token,
+        expires_at: Some(response.expire_time),
+    })
+}
@@ -0,0 +1,23 @@
+use std::env;
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::gcloud::auth::Token;
+use crate::gcloud::auth::TokenResponse;
+use crate::gcloud::auth::fetch;
+use crate::util::http_client::HTTP_CLIENT;
+
+const DEFAULT_HOST: &str = "metadata.google.internal";
+
+// token of service account attached to GCE vm or GKE workload identity, GCE_METADATA_HOST overrides host, e.g. to use local stub
+pub async fn token() -> Result<Token> {
+    let host = env::var("GCE_METADATA_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_owned());
+    let url = format!("http://{host}/computeMetadata/v1/instance/service-accounts/default/token");
+    let request = HTTP_CLIENT.get(&url).header("Metadata-Flavor", "Google");
+    let response: TokenResponse = fetch("metadata server", &url, request).await.map_err(|err| match err {
+        // metadata server is last resort, e.g. not reachable outside gcloud
+        Error::Gcloud(message) => Error::Gcloud(format!("{message}, please setup gcloud or set GCLOUD_AUTH_TOKEN env")),
+        err => err,
+    })?;
+    Ok(response.into())
+}
@@ -1,6 +1,11 @@
+use crate::error::Error;
+use crate::error::Result;
 use crate::gcloud;
+use crate::gcloud::Api;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use chrono::DateTime;
+use chrono::Utc;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
@@ -44,45 +49,85 @@ struct AddSecretVersionRequest {
     payload: SecretPayload,
 }
 
-#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
-struct SecretVersion {
-    name: String,
+pub struct SecretVersion {
+    pub name: String,
+    #[serde(rename(deserialize = "createTime"))]
+    pub create_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListSecretVersionsResponse {
+    #[serde(default)]
+    versions: Vec<SecretVersion>,
+    #[serde(rename(deserialize = "nextPageToken"))]
+    next_page_token: Option<String>,
 }
 
-pub async fn get_or_create(project: &str, name: &str, env: &str) -> String {
-    let url = format!("https://secretmanager.googleapis.com/v1/projects/{project}/secrets/{name}/versions/latest:access");
-    let response: Option<AccessSecretVersion> = gcloud::get(&url).await;
+#[derive(Serialize, Debug)]
+struct DisableSecretVersionRequest {}
+
+pub async fn get(project: &str, name: &str) -> Result<Option<String>> {
+    let url = Api::SecretManager.url(&format!("projects/{project}/secrets/{name}/versions/latest:access"));
+    let response: Option<AccessSecretVersion> = gcloud::get(&url).await?;
+
+    response
+        .map(|version| {
+            let data = BASE64_STANDARD
+                .decode(&version.payload.data)
+                .map_err(|err| Error::Gcloud(format!("secret payload should be in base64 encoding, secret={name}, err={err}")))?;
+            String::from_utf8(data).map_err(|err| Error::Gcloud(format!("secret should be in utf-8, secret={name}, err={err}")))
+        })
+        .transpose()
+}
 
-    match response {
-        Some(version) => {
-            let data = version.payload.data;
-            let data = BASE64_STANDARD.decode(&data).expect("payload should be in base64 encoding");
-            String::from_utf8(data).expect("data should be in utf-8")
+pub async fn create(project: &str, name: &str, env: &str, value: &str) -> Result<()> {
+    info!(name, "create secret");
+    let url = Api::SecretManager.url(&format!("projects/{project}/secrets?secretId={name}"));
+    let mut request = CreateSecretRequest::default();
+    request.labels.insert("env".to_string(), env.to_string());
+    let _: CreateSecretResponse = gcloud::post(&url, &request).await?;
+    add_secret_version(project, name, value).await
+}
+
+pub async fn enabled_versions(project: &str, name: &str) -> Result<Vec<SecretVersion>> {
+    let mut versions = vec![];
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut url = Api::SecretManager.url(&format!("projects/{project}/secrets/{name}/versions?filter=state:ENABLED"));
+        if let Some(token) = &page_token {
+            url.push_str(&format!("&pageToken={token}"));
         }
-        None => {
-            info!(name, "secret not found, create new one");
-            create(project, name, env).await;
-            let value = Uuid::new_v4().to_string();
-            add_secret_version(project, name, &value).await;
-            value
+        let response: ListSecretVersionsResponse = gcloud::get(&url)
+            .await?
+            .ok_or_else(|| Error::Config(format!("secret not found, project={project}, secret={name}")))?;
+        versions.extend(response.versions);
+        page_token = response.next_page_token;
+        if page_token.is_none() {
+            return Ok(versions);
         }
     }
 }
 
-async fn create(project: &str, name: &str, env: &str) {
-    let url = format!("https://secretmanager.googleapis.com/v1/projects/{project}/secrets?secretId={name}");
-    let mut request = CreateSecretRequest::default();
-    request.labels.insert("env".to_string(), env.to_string());
-    let _: CreateSecretResponse = gcloud::post(&url, &request).await;
+// version is full resource name, e.g. projects/{project}/secrets/{name}/versions/{version}
+pub async fn disable_version(version: &str) -> Result<()> {
+    info!(version, "disable secret version");
+    let url = Api::SecretManager.url(&format!("{version}:disable"));
+    let _: SecretVersion = gcloud::post(&url, &DisableSecretVersionRequest {}).await?;
+    Ok(())
+}
+
+pub fn generate_password() -> String {
+    Uuid::new_v4().to_string()
 }
 
-async fn add_secret_version(project: &str, name: &str, value: &str) {
-    let url = format!("https://secretmanager.googleapis.com/v1/projects/{project}/secrets/{name}:addVersion");
+pub async fn add_secret_version(project: &str, name: &str, value: &str) -> Result<()> {
+    let url = Api::SecretManager.url(&format!("projects/{project}/secrets/{name}:addVersion"));
     let request = AddSecretVersionRequest {
         payload: SecretPayload {
             data: BASE64_STANDARD.encode(value),
         },
     };
-    let _: SecretVersion = gcloud::post(&url, &request).await;
+    let _: SecretVersion = gcloud::post(&url, &request).await?;
+    Ok(())
 }
@@ -1,7 +1,17 @@
+use crate::error::Error;
+use crate::error::Result;
 use crate::gcloud;
+use crate::gcloud::Api;
 use serde::Deserialize;
 use serde::Serialize;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::info;
+use tracing::warn;
+
+const OPERATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// setting database flags restarts instance, which may take several minutes
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(600);
 
 #[derive(Deserialize, Debug)]
 pub struct GetSQLInstanceResponse {
@@ -9,6 +19,30 @@ pub struct GetSQLInstanceResponse {
     _kind: String,
     #[serde(rename(deserialize = "ipAddresses"))]
     addresses: Vec<IPAddress>,
+    pub settings: Settings,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Settings {
+    #[serde(rename(deserialize = "databaseFlags"), default)]
+    pub database_flags: Vec<DatabaseFlag>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DatabaseFlag {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Debug)]
+struct PatchSQLInstanceRequest {
+    settings: PatchSettings,
+}
+
+#[derive(Serialize, Debug)]
+struct PatchSettings {
+    #[serde(rename(serialize = "databaseFlags"))]
+    database_flags: Vec<DatabaseFlag>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -26,41 +60,77 @@ struct User {
     password: String,
 }
 
+#[derive(Serialize, Debug)]
+struct IamUser {
+    name: String,
+    #[serde(rename(serialize = "type"))]
+    user_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListUsersResponse {
+    #[serde(default)]
+    items: Vec<SQLUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SQLUser {
+    name: String,
+    // only set for iam users, e.g. CLOUD_IAM_USER
+    #[serde(rename(deserialize = "type"), default)]
+    user_type: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct Operation {
     #[serde(rename(deserialize = "kind"))]
     _kind: String,
+    name: String,
+    status: String,
+    error: Option<OperationErrors>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OperationErrors {
+    #[serde(default)]
+    errors: Vec<OperationError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OperationError {
+    code: String,
+    message: Option<String>,
 }
 
 impl GetSQLInstanceResponse {
-    pub fn public_address(&self) -> &str {
-        self.addresses
-            .iter()
-            .find(|ip| ip.ip_type == "PRIMARY")
-            .map(|ip| ip.address.as_str())
-            .expect("public ip should not be null")
+    pub fn public_address(&self) -> Result<&str> {
+        self.address("PRIMARY")
     }
 
-    pub fn private_address(&self) -> &str {
+    pub fn private_address(&self) -> Result<&str> {
+        self.address("PRIVATE")
+    }
+
+    fn address(&self, ip_type: &str) -> Result<&str> {
         self.addresses
             .iter()
-            .find(|ip| ip.ip_type == "PRIVATE")
+            .find(|ip| ip.ip_type == ip_type)
             .map(|ip| ip.address.as_str())
-            .expect("private ip should not be null")
+            .ok_or_else(|| Error::Gcloud(format!("sql instance has no ip address, type={ip_type}")))
     }
 }
 
-pub async fn get_sql_instance(project: &str, instance: &str) -> GetSQLInstanceResponse {
-    let url = format!("https://sqladmin.googleapis.com/v1/projects/{project}/instances/{instance}");
+pub async fn get_sql_instance(project: &str, instance: &str) -> Result<GetSQLInstanceResponse> {
+    let url = Api::SQLAdmin.url(&format!("projects/{project}/instances/{instance}"));
     gcloud::get(&url)
-        .await
-        .unwrap_or_else(|| panic!("instance not found, instance={instance}"))
+        .await?
+        .ok_or_else(|| Error::Config(format!("instance not found, project={project}, instance={instance}")))
 }
 
-pub async fn set_password(project: &str, instance: &str, user: &str, password: &str) {
+pub async fn set_password(project: &str, instance: &str, user: &str, password: &str) -> Result<()> {
     info!(instance, user, "change sql instance root password");
-    let url = format!("https://sqladmin.googleapis.com/v1/projects/{project}/instances/{instance}/users");
-    let _: Operation = gcloud::post(
+    let url = Api::SQLAdmin.url(&format!("projects/{project}/instances/{instance}/users"));
+    let operation: Operation = gcloud::post(
         &url,
         &User {
             name: user.to_owned(),
@@ -68,5 +138,97 @@ pub async fn set_password(project: &str, instance: &str, user: &str, password: &
             password: password.to_string(),
         },
     )
-    .await;
+    .await?;
+    wait_operation(project, operation).await
+}
+
+pub async fn list_users(project: &str, instance: &str) -> Result<Vec<String>> {
+    Ok(sql_users(project, instance).await?.into_iter().map(|user| user.name).collect())
+}
+
+// users created by cloud sql for members of iam group on first login, in email
+pub async fn list_iam_group_members(project: &str, instance: &str) -> Result<Vec<String>> {
+    Ok(sql_users(project, instance)
+        .await?
+        .into_iter()
+        .filter(|user| {
+            matches!(
+                user.user_type.as_deref(),
+                Some("CLOUD_IAM_GROUP_USER" | "CLOUD_IAM_GROUP_SERVICE_ACCOUNT")
+            )
+        })
+        .map(|user| user.name)
+        .collect())
+}
+
+async fn sql_users(project: &str, instance: &str) -> Result<Vec<SQLUser>> {
+    let url = Api::SQLAdmin.url(&format!("projects/{project}/instances/{instance}/users"));
+    let response: ListUsersResponse = gcloud::get(&url)
+        .await?
+        .ok_or_else(|| Error::Config(format!("instance not found, project={project}, instance={instance}")))?;
+    Ok(response.items)
+}
+
+// user_type is one of CLOUD_IAM_USER, CLOUD_IAM_SERVICE_ACCOUNT and CLOUD_IAM_GROUP
+pub async fn create_iam_user(project: &str, instance: &str, user: &str, user_type: &str) -> Result<()> {
+    info!(instance, user, user_type, "create sql instance iam user");
+    let url = Api::SQLAdmin.url(&format!("projects/{project}/instances/{instance}/users"));
+    let operation: Operation = gcloud::post(
+        &url,
+        &IamUser {
+            name: user.to_owned(),
+            user_type: user_type.to_owned(),
+        },
+    )
+    .await?;
+    wait_operation(project, operation).await
+}
+
+// patch replaces all database flags, so existing flags must be included
+pub async fn set_database_flags(project: &str, instance: &str, flags: &[DatabaseFlag]) -> Result<()> {
+    warn!(instance, "change sql instance database flags, instance may restart");
+    let url = Api::SQLAdmin.url(&format!("projects/{project}/instances/{instance}"));
+    let operation: Operation = gcloud::patch(
+        &url,
+        &PatchSQLInstanceRequest {
+            settings: PatchSettings {
+                database_flags: flags.to_vec(),
+            },
+        },
+    )
+    .await?;
+    wait_operation(project, operation).await
+}
+
+// mutating calls return long-running operation, changes are only applied once operation is DONE
+async fn wait_operation(project: &str, mut operation: Operation) -> Result<()> {
+    let deadline = Instant::now() + OPERATION_TIMEOUT;
+    while operation.status != "DONE" {
+        if Instant::now() >= deadline {
+            return Err(Error::Gcloud(format!(
+                "sql admin operation timed out, operation={}, status={}, timeout={OPERATION_TIMEOUT:?}",
+                operation.name, operation.status
+            )));
+        }
+        info!(operation = operation.name, status = operation.status, "wait for sql admin operation");
+        tokio::time::sleep(OPERATION_POLL_INTERVAL).await;
+        let url = Api::SQLAdmin.url(&format!("projects/{project}/operations/{}", operation.name));
+        operation = gcloud::get(&url)
+            .await?
+            .ok_or_else(|| Error::Gcloud(format!("operation not found, operation={}", operation.name)))?;
+    }
+
+    if let Some(error) = operation.error {
+        let errors: Vec<String> = error
+            .errors
+            .iter()
+            .map(|error| format!("{}: {}", error.code, error.message.as_deref().unwrap_or_default()))
+            .collect();
+        return Err(Error::Gcloud(format!(
+            "sql admin operation failed, operation={}, errors=[{}]",
+            operation.name,
+            errors.join(", ")
+        )));
+    }
+    Ok(())
 }
@@ -1,7 +1,12 @@
+use std::process;
+
 use anyhow::Result;
+use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
 use command::completion::Completion;
+use command::rotate_password::RotatePassword;
+use command::schema::Schema;
 use command::sync_db::SyncDB;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::Layer;
@@ -11,6 +16,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 mod command;
 mod config;
 mod db;
+mod error;
 mod gcloud;
 mod kube;
 mod util;
@@ -21,19 +27,42 @@ mod util;
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(
+        long,
+        global = true,
+        help = "call gcloud apis as given service account, e.g. gm@project.iam.gserviceaccount.com"
+    )]
+    impersonate_service_account: Option<String>,
 }
 
 #[derive(Subcommand)]
 #[command(arg_required_else_help(true))]
 pub enum Commands {
     #[command(about = "sync db")]
-    DB(SyncDB),
+    DB(DB),
     #[command(about = "generate shell completion")]
     Completion(Completion),
+    #[command(about = "print json schema of config files")]
+    Schema(Schema),
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct DB {
+    #[command(subcommand)]
+    command: Option<DBCommands>,
+    #[command(flatten)]
+    sync: SyncDB,
+}
+
+#[derive(Subcommand)]
+pub enum DBCommands {
+    #[command(about = "rotate db user passwords")]
+    Rotate(RotatePassword),
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -45,9 +74,22 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
+    if let Err(err) = run(cli).await {
+        eprintln!("error: {err:#}");
+        process::exit(error::exit_code(&err));
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    if let Some(service_account) = cli.impersonate_service_account {
+        gcloud::impersonate(service_account);
+    }
     match &cli.command {
-        Commands::DB(command) => command.execute().await?,
+        Commands::DB(command) => match &command.command {
+            Some(DBCommands::Rotate(command)) => command.execute().await,
+            None => command.sync.execute().await,
+        },
         Commands::Completion(command) => command.execute(),
+        Commands::Schema(command) => command.execute(),
     }
-    Ok(())
 }
@@ -3,11 +3,12 @@ use std::fmt;
 use serde::de;
 use serde::Serialize;
 
-pub fn from_json<'a, T>(json: &'a str) -> T
+// json is not included in error, as it may contain secrets
+pub fn from_json<'a, T>(json: &'a str) -> Result<T, serde_json::Error>
 where
     T: de::Deserialize<'a>,
 {
-    serde_json::from_str(json).unwrap_or_else(|err| panic!("failed to deserialize to json, json={json}, error={err}"))
+    serde_json::from_str(json)
 }
 
 pub fn to_json<T>(object: &T) -> String